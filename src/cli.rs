@@ -0,0 +1,94 @@
+use std::path::PathBuf;
+
+pub const USAGE: &str = "\
+Usage: dual-key-remap [--config <path>] [command]
+
+Commands:
+  run        Start remapping (default)
+  check      Parse the config file and print the resolved remaps
+  list-keys  Print all key names usable in the config file
+
+Options:
+  --config <path>  Config file to use instead of config.toml / config.txt
+                   next to the executable
+  --help           Print this help";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Command {
+    Run,
+    Check,
+    ListKeys,
+    Help,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub struct Args {
+    pub command: Command,
+    pub config_path: Option<PathBuf>,
+}
+
+// 参数不包括程序名
+pub fn parse_args<I: IntoIterator<Item = String>>(args: I) -> Result<Args, String> {
+    let mut command = None;
+    let mut config_path = None;
+    let mut args = args.into_iter();
+    
+    while let Some(arg) = args.next() {
+        let next_command = match arg.as_str() {
+            "--config" | "-c" => {
+                let path = args.next().ok_or("--config requires a path")?;
+                config_path = Some(PathBuf::from(path));
+                continue;
+            }
+            _ if arg.starts_with("--config=") => {
+                config_path = Some(PathBuf::from(&arg["--config=".len()..]));
+                continue;
+            }
+            "--help" | "-h" | "help" => Command::Help,
+            "run" => Command::Run,
+            "check" => Command::Check,
+            "list-keys" => Command::ListKeys,
+            _ if arg.starts_with('-') => return Err(format!("Unknown option '{}'", arg)),
+            _ => return Err(format!("Unknown command '{}'", arg)),
+        };
+        
+        if command.is_some_and(|command| command != next_command) {
+            return Err(format!("Unexpected argument '{}', only one command can be given", arg));
+        }
+        command = Some(next_command);
+    }
+    
+    Ok(Args {
+        command: command.unwrap_or(Command::Run),
+        config_path,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    
+    fn parse(args: &[&str]) -> Result<Args, String> {
+        parse_args(args.iter().map(|arg| arg.to_string()))
+    }
+    
+    #[test]
+    fn commands_and_config_path() {
+        assert_eq!(parse(&[]).unwrap(), Args { command: Command::Run, config_path: None });
+        assert_eq!(parse(&["check"]).unwrap().command, Command::Check);
+        assert_eq!(parse(&["list-keys"]).unwrap().command, Command::ListKeys);
+        assert_eq!(parse(&["--help"]).unwrap().command, Command::Help);
+        
+        let args = parse(&["--config", "my.toml", "check"]).unwrap();
+        assert_eq!(args, Args { command: Command::Check, config_path: Some(PathBuf::from("my.toml")) });
+        assert_eq!(parse(&["run", "--config=a.txt"]).unwrap().config_path, Some(PathBuf::from("a.txt")));
+    }
+    
+    #[test]
+    fn invalid_arguments() {
+        assert!(parse(&["--config"]).is_err());
+        assert!(parse(&["--verbose"]).is_err());
+        assert!(parse(&["start"]).is_err());
+        assert!(parse(&["check", "run"]).is_err());
+    }
+}
@@ -1,41 +1,456 @@
-use crate::keys::{find_key_by_name, KeyDef};
+use crate::error::{suggest, ConfigError, Span};
+use crate::input::can_send;
+use crate::keys::{generic_modifier, parse_key, KeyDef};
+use serde::de::IgnoredAny;
+use serde::{Deserialize, Deserializer};
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
 use std::fs;
+use std::ops::Range;
 use std::path::Path;
+use toml::Spanned;
 
+// 一次输出：同时按下的组合键，或一段文本
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Stroke {
+    Chord(Vec<KeyDef>),
+    Text(String),
+}
+
+impl fmt::Display for Stroke {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Stroke::Chord(keys) => {
+                let names: Vec<&str> = keys.iter().map(|key_def| key_def.name).collect();
+                write!(f, "{}", names.join("+"))
+            }
+            Stroke::Text(text) => write!(f, "\"{}\"", text),
+        }
+    }
+}
+
+// 按顺序发送的一串输出，如 "ESCAPE, CTRL+S"
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Sequence(pub Vec<Stroke>);
+
+impl fmt::Display for Sequence {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let strokes: Vec<String> = self.0.iter().map(|stroke| stroke.to_string()).collect();
+        write!(f, "{}", strokes.join(", "))
+    }
+}
+
+// 与其他键一起按下时的行为：作为一个或多个修饰键（如 CTRL+ALT+SHIFT+LWIN），或激活一个层
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum WithOther {
+    Chord(Vec<KeyDef>),
+    Layer(String),
+}
+
+impl fmt::Display for WithOther {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            WithOther::Chord(keys) => write!(f, "{}", Stroke::Chord(keys.clone())),
+            WithOther::Layer(name) => write!(f, "layer:{}", name),
+        }
+    }
+}
+
+// 决定 when_alone 还是 with_other 的策略
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum HoldMode {
+    // 按住期间一有其他键按下就切换为 with_other
+    #[default]
+    HoldOnOtherPress,
+    // 按住期间另一个键完整地按下并释放才切换为 with_other，其间的按键先缓冲
+    PermissiveHold,
+    // 只由 hold_timeout_ms（tapping term）决定：期间松开为 when_alone，超时为 with_other，其间的按键先缓冲
+    Balanced,
+}
+
+impl HoldMode {
+    fn parse(value: &str) -> Option<Self> {
+        match value {
+            "hold_on_other_press" => Some(HoldMode::HoldOnOtherPress),
+            "permissive_hold" => Some(HoldMode::PermissiveHold),
+            "balanced" => Some(HoldMode::Balanced),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for HoldMode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            HoldMode::HoldOnOtherPress => write!(f, "hold_on_other_press"),
+            HoldMode::PermissiveHold => write!(f, "permissive_hold"),
+            HoldMode::Balanced => write!(f, "balanced"),
+        }
+    }
+}
+
+// 输入按哪种键码匹配配置中的键
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum MatchBy {
+    // 虚拟键码，随当前键盘布局变化
+    #[default]
+    VirtualKey,
+    // 扫描码（扩展键带 0xE0 前缀），对应物理按键，可以区分回车和小键盘回车
+    ScanCode,
+}
+
+impl MatchBy {
+    fn parse(value: &str) -> Option<Self> {
+        match value {
+            "virtual_key" => Some(MatchBy::VirtualKey),
+            "scan_code" => Some(MatchBy::ScanCode),
+            _ => None,
+        }
+    }
+    
+    // 配置中的键在该方式下的标识
+    pub fn key_id(self, key_def: &KeyDef) -> u32 {
+        match self {
+            MatchBy::VirtualKey => key_def.virt_code,
+            MatchBy::ScanCode => key_def.scan_code,
+        }
+    }
+    
+    // 输入事件在该方式下的标识
+    pub fn input_id(self, virt_code: u32, scan_code: u32) -> u32 {
+        match self {
+            MatchBy::VirtualKey => virt_code,
+            MatchBy::ScanCode => scan_code,
+        }
+    }
+}
+
+// 其他软件（AutoHotkey、屏幕键盘等）注入的输入如何处理；本程序自己注入的输入总是忽略
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ForeignInjected {
+    // 直接放行，不影响重映射键的状态
+    Ignore,
+    // 作为其他输入，按住的重映射键切换为 with_other
+    #[default]
+    Other,
+    // 与物理按键一样参与重映射
+    Remap,
+}
+
+impl ForeignInjected {
+    fn parse(value: &str) -> Option<Self> {
+        match value {
+            "ignore" => Some(ForeignInjected::Ignore),
+            "other" => Some(ForeignInjected::Other),
+            "remap" => Some(ForeignInjected::Remap),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for ForeignInjected {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ForeignInjected::Ignore => write!(f, "ignore"),
+            ForeignInjected::Other => write!(f, "other"),
+            ForeignInjected::Remap => write!(f, "remap"),
+        }
+    }
+}
+
+// 层：按住层键时，其他键按该表重映射
 #[derive(Debug, Clone)]
+pub struct LayerConfig {
+    pub name: String,
+    pub mappings: Vec<(KeyDef, KeyDef)>,
+}
+
+// 未知字段在 parse_toml_config 中单独检查，以便作为警告
+#[derive(Debug, Clone, Deserialize)]
 pub struct RemapConfig {
+    #[serde(rename = "remap_key", deserialize_with = "deserialize_key")]
     pub from: KeyDef,
-    pub to_when_alone: KeyDef,
-    pub to_with_other: KeyDef,
+    #[serde(rename = "when_alone", deserialize_with = "deserialize_sequence")]
+    pub to_when_alone: Sequence,
+    #[serde(rename = "with_other", deserialize_with = "deserialize_with_other")]
+    pub to_with_other: WithOther,
+    // 按住超过该时长（毫秒）后，即使没有其他按键也切换为 with_other
+    pub hold_timeout_ms: Option<u64>,
+    pub hold_mode: Option<HoldMode>,
+    // 单击后在该时长（毫秒）内再次按下，则按住 when_alone 而不是进入双功能状态
+    pub quick_tap_ms: Option<u64>,
+    // 超时切换为 with_other 后若一直没有其他按键，释放时仍发送 when_alone
+    pub retro_tap: Option<bool>,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Default, Deserialize)]
 pub struct Config {
+    #[serde(rename = "remap", default)]
     pub remaps: Vec<RemapConfig>,
+    #[serde(default, deserialize_with = "deserialize_layers")]
+    pub layers: Vec<LayerConfig>,
+    // 全局默认的 hold_timeout_ms、hold_mode、quick_tap_ms 和 retro_tap，未单独设置的 remap 使用该值
+    pub hold_timeout_ms: Option<u64>,
+    pub hold_mode: Option<HoldMode>,
+    pub quick_tap_ms: Option<u64>,
+    pub retro_tap: Option<bool>,
+    // Linux 下要接管的输入设备：设备路径或设备名称的一部分
+    pub device: Option<String>,
+    #[serde(default)]
+    pub match_by: MatchBy,
+    #[serde(default)]
+    pub foreign_injected: ForeignInjected,
+    // 记录每个输入、状态切换和输出，有 log_file 时写入该文件，否则输出到控制台
+    #[serde(default)]
+    pub debug: bool,
+    pub log_file: Option<String>,
+    // 未知的设置作为错误而不是警告
+    #[serde(default)]
+    pub strict: bool,
+    // 解析时忽略的未知设置
+    #[serde(skip)]
+    pub warnings: Vec<ConfigError>,
 }
 
-impl Default for Config {
-    fn default() -> Self {
-        Self {
-            remaps: Vec::new(),
+// 设置项名称，用于给拼错的设置提供建议
+const SETTINGS: &[&str] = &[
+    "remap_key", "when_alone", "with_other", "hold_timeout_ms", "hold_mode",
+    "quick_tap_ms", "retro_tap", "device", "layer", "map", "debug", "log_file", "strict", "match_by",
+    "foreign_injected",
+];
+
+// TOML 格式的顶层设置和 [[remap]] 中的设置
+const TOML_SETTINGS: &[&str] = &[
+    "remap", "layers", "hold_timeout_ms", "hold_mode", "quick_tap_ms", "retro_tap", "device",
+    "match_by", "foreign_injected", "debug", "log_file", "strict",
+];
+const TOML_REMAP_SETTINGS: &[&str] = &[
+    "remap_key", "when_alone", "with_other", "hold_timeout_ms", "hold_mode", "quick_tap_ms", "retro_tap",
+];
+
+// 只读取 TOML 中设置项的名称及其位置，用于发现未知的设置
+#[derive(Deserialize)]
+struct TomlSettingNames {
+    #[serde(default)]
+    remap: Vec<BTreeMap<Spanned<String>, IgnoredAny>>,
+}
+
+// 解析单个值的错误；无效的键名单独区分，以便标出它在行中的位置并给出建议
+#[derive(Debug)]
+pub enum ValueError {
+    UnknownKey(String),
+    Invalid(String),
+}
+
+impl fmt::Display for ValueError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ValueError::UnknownKey(name) => write!(f, "{}", ConfigError::unknown_key(None, name).message()),
+            ValueError::Invalid(message) => write!(f, "{}", message),
         }
     }
 }
 
-pub fn load_config<P: AsRef<Path>>(path: P) -> Result<Config, String> {
+fn deserialize_key<'de, D: Deserializer<'de>>(deserializer: D) -> Result<KeyDef, D::Error> {
+    let name = String::deserialize(deserializer)?;
+    parse_key_value(&name).map_err(serde::de::Error::custom)
+}
+
+fn deserialize_sequence<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Sequence, D::Error> {
+    let value = String::deserialize(deserializer)?;
+    parse_sequence(&value).map_err(serde::de::Error::custom)
+}
+
+fn deserialize_with_other<'de, D: Deserializer<'de>>(deserializer: D) -> Result<WithOther, D::Error> {
+    let value = String::deserialize(deserializer)?;
+    parse_with_other(&value).map_err(serde::de::Error::custom)
+}
+
+fn deserialize_layers<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<LayerConfig>, D::Error> {
+    let layers = BTreeMap::<String, BTreeMap<String, String>>::deserialize(deserializer)?;
+    
+    layers.into_iter()
+        .map(|(name, mappings)| {
+            let mappings = mappings.iter()
+                .map(|(from, to)| parse_mapping(from, to))
+                .collect::<Result<_, _>>()
+                .map_err(serde::de::Error::custom)?;
+            Ok(LayerConfig { name, mappings })
+        })
+        .collect()
+}
+
+fn parse_key_value(name: &str) -> Result<KeyDef, ValueError> {
+    parse_key(name).ok_or_else(|| ValueError::UnknownKey(name.to_string()))
+}
+
+// "layer:名称" 表示激活层，否则为用 + 连接的键名
+fn parse_with_other(value: &str) -> Result<WithOther, ValueError> {
+    match value.strip_prefix("layer:") {
+        Some(name) if !name.trim().is_empty() => Ok(WithOther::Layer(name.trim().to_string())),
+        Some(_) => Err(ValueError::Invalid("layer:<name> needs a layer name".to_string())),
+        None => value.split('+')
+            .map(|name| parse_key_value(name.trim()))
+            .collect::<Result<_, _>>()
+            .map(WithOther::Chord),
+    }
+}
+
+// 逗号分隔的序列，每项是用 + 连接的组合键或带引号的文本
+pub fn parse_sequence(value: &str) -> Result<Sequence, ValueError> {
+    let mut strokes = Vec::new();
+    let mut item = String::new();
+    let mut in_quotes = false;
+    
+    for c in value.chars().chain(std::iter::once(',')) {
+        match c {
+            '"' => {
+                in_quotes = !in_quotes;
+                item.push(c);
+            }
+            ',' if !in_quotes => {
+                strokes.push(parse_stroke(item.trim())?);
+                item.clear();
+            }
+            _ => item.push(c),
+        }
+    }
+    
+    if in_quotes {
+        return Err(ValueError::Invalid(format!("unterminated text in '{}'", value)));
+    }
+    Ok(Sequence(strokes))
+}
+
+fn parse_stroke(item: &str) -> Result<Stroke, ValueError> {
+    if let Some(text) = item.strip_prefix('"').and_then(|rest| rest.strip_suffix('"')) {
+        if text.is_empty() || text.contains('"') {
+            return Err(ValueError::Invalid(format!("invalid text {}", item)));
+        }
+        return Ok(Stroke::Text(text.to_string()));
+    }
+    
+    let keys = item.split('+')
+        .map(|name| parse_key_value(name.trim()))
+        .collect::<Result<_, _>>()?;
+    Ok(Stroke::Chord(keys))
+}
+
+// 开关类设置接受 true/false 和 1/0
+fn parse_bool(value: &str) -> Option<bool> {
+    match value.to_lowercase().as_str() {
+        "true" | "1" => Some(true),
+        "false" | "0" => Some(false),
+        _ => None,
+    }
+}
+
+fn parse_mapping(from: &str, to: &str) -> Result<(KeyDef, KeyDef), ValueError> {
+    Ok((parse_key_value(from.trim())?, parse_key_value(to.trim())?))
+}
+
+// 给值的解析错误加上位置，value_start 是值在行中的字节位置；无效键名只标出该键名
+fn value_error(line_num: usize, line: &str, value: &str, value_start: usize, err: ValueError) -> ConfigError {
+    match err {
+        ValueError::UnknownKey(name) => {
+            let span = match value.find(name.as_str()) {
+                Some(index) => Span::at(line_num, line, value_start + index, name.len()),
+                None => Span::at(line_num, line, value_start, value.len()),
+            };
+            ConfigError::unknown_key(Some(span), &name)
+        }
+        ValueError::Invalid(message) => ConfigError::InvalidValue {
+            span: Span::at(line_num, line, value_start, value.len()),
+            message,
+        },
+    }
+}
+
+pub fn load_config<P: AsRef<Path>>(path: P) -> Result<Config, ConfigError> {
     let content = fs::read_to_string(&path)
-        .map_err(|e| format!("Cannot open configuration file '{}': {}", path.as_ref().display(), e))?;
+        .map_err(|e| ConfigError::Io { path: path.as_ref().display().to_string(), message: e.to_string() })?;
+    
+    // 根据扩展名选择格式，无法判断时看内容里是否有 TOML 表头
+    let is_toml = match path.as_ref().extension().and_then(|ext| ext.to_str()) {
+        Some(ext) if ext.eq_ignore_ascii_case("toml") => true,
+        Some(ext) if ext.eq_ignore_ascii_case("txt") => false,
+        _ => content.lines().any(|line| line.trim_start().starts_with('[')),
+    };
     
-    parse_config(&content)
+    if is_toml {
+        parse_toml_config(&content)
+    } else {
+        parse_config(&content)
+    }
+}
+
+pub fn parse_toml_config(content: &str) -> Result<Config, ConfigError> {
+    let mut config: Config = toml::from_str(content).map_err(toml_error)?;
+    
+    // 与旧格式一致：未知设置默认作为警告，strict 时报告第一个
+    let mut unknown = unknown_toml_settings(content)?;
+    if config.strict && !unknown.is_empty() {
+        return Err(unknown.remove(0));
+    }
+    config.warnings = unknown;
+    
+    apply_defaults(&mut config);
+    check_layers(&config)?;
+    validate(&mut config)?;
+    Ok(config)
+}
+
+fn toml_error(err: toml::de::Error) -> ConfigError {
+    ConfigError::Toml(err.to_string().trim_end().to_string())
+}
+
+// 按出现顺序列出顶层和 [[remap]] 中的未知设置；[layers.*] 中是键名而不是设置，不检查
+fn unknown_toml_settings(content: &str) -> Result<Vec<ConfigError>, ConfigError> {
+    let top: BTreeMap<Spanned<String>, IgnoredAny> = toml::from_str(content).map_err(toml_error)?;
+    let names: TomlSettingNames = toml::from_str(content).map_err(toml_error)?;
+    
+    let mut unknown: Vec<(Range<usize>, &str, &[&str])> = top.keys()
+        .map(|name| (name, TOML_SETTINGS))
+        .chain(names.remap.iter().flat_map(|remap| remap.keys().map(|name| (name, TOML_REMAP_SETTINGS))))
+        .filter(|(name, settings)| !settings.contains(&name.get_ref().as_str()))
+        .map(|(name, settings)| (name.span(), name.get_ref().as_str(), settings))
+        .collect();
+    unknown.sort_by_key(|(span, _, _)| span.start);
+    
+    Ok(unknown.into_iter()
+        .map(|(span, name, settings)| ConfigError::UnknownSetting {
+            span: toml_span(content, span),
+            name: name.to_string(),
+            suggestion: suggest(name, settings.iter().copied()),
+        })
+        .collect())
+}
+
+// TOML 中的字节范围所在的行和列
+fn toml_span(content: &str, range: Range<usize>) -> Span {
+    let line_start = content[..range.start].rfind('\n').map_or(0, |index| index + 1);
+    let line_end = content[range.start..].find('\n').map_or(content.len(), |index| range.start + index);
+    let line_num = content[..range.start].matches('\n').count() + 1;
+    
+    Span::at(line_num, content[line_start..line_end].trim_end_matches('\r'), range.start - line_start, range.len())
 }
 
-fn parse_config(content: &str) -> Result<Config, String> {
+pub fn parse_config(content: &str) -> Result<Config, ConfigError> {
     let mut config = Config::default();
     let mut current_remap: Option<RemapConfigBuilder> = None;
+    // 当前正在定义的层（config.layers 中的下标）
+    let mut current_layer: Option<usize> = None;
+    // 已重映射的键：(虚拟键码, 扫描码) -> 所在行
+    let mut remap_lines: HashMap<(u32, u32), usize> = HashMap::new();
+    let mut unknown: Vec<ConfigError> = Vec::new();
     
-    for (line_num, line) in content.lines().enumerate() {
+    for (line_num, raw_line) in content.lines().enumerate() {
         let line_num = line_num + 1;
-        let line = line.trim();
+        let line = raw_line.trim();
+        let line_start = raw_line.len() - raw_line.trim_start().len();
         
         // 跳过空行和注释
         if line.is_empty() || line.starts_with('#') {
@@ -43,103 +458,663 @@ fn parse_config(content: &str) -> Result<Config, String> {
         }
         
         // 解析 key=value 格式
-        let parts: Vec<&str> = line.splitn(2, '=').collect();
-        if parts.len() != 2 {
-            return Err(format!("Config error (line {}): expected key=value", line_num));
-        }
+        let Some((key, raw_value)) = line.split_once('=') else {
+            return Err(ConfigError::Syntax {
+                span: Span::at(line_num, raw_line, line_start, line.len()),
+                message: "expected key=value".to_string(),
+            });
+        };
         
-        let key = parts[0].trim();
-        let value = parts[1].trim();
+        // 值在行中的字节位置：等号之后，跳过前导空白
+        let value_start = line_start + key.len() + 1 + (raw_value.len() - raw_value.trim_start().len());
+        let key = key.trim();
+        let value = raw_value.trim();
+        let value_span = || Span::at(line_num, raw_line, value_start, value.len());
+        let orphan = |setting, parent| ConfigError::OrphanSetting {
+            span: Span::at(line_num, raw_line, line_start, key.len()),
+            setting,
+            parent,
+        };
         
         match key {
             "remap_key" => {
-                // 如果有未完成的remap，检查是否完整
-                if let Some(builder) = current_remap.take() {
-                    if !builder.is_complete() {
-                        return Err(format!(
-                            "Config error (line {}): Incomplete remapping. Each remap needs remap_key, when_alone and with_other before another remap_key.",
-                            line_num
-                        ));
-                    }
-                    config.remaps.push(builder.build()?);
-                }
+                finish_remap(&mut config, current_remap.take())
+                    .map_err(|e| explain_incomplete(e, &mut unknown))?;
+                current_layer = None;
+                
+                let key_def = parse_key_value(value)
+                    .map_err(|e| value_error(line_num, raw_line, value, value_start, e))?;
                 
-                let key_def = find_key_by_name(value)
-                    .ok_or_else(|| format!("Config error (line {}): invalid key name '{}'", line_num, value))?;
+                if let Some(&first_line) = remap_lines.get(&(key_def.virt_code, key_def.scan_code)) {
+                    return Err(ConfigError::DuplicateRemapKey {
+                        span: Some(value_span()),
+                        key: key_def.name.to_string(),
+                        first_line: Some(first_line),
+                    });
+                }
+                remap_lines.insert((key_def.virt_code, key_def.scan_code), line_num);
                 
-                current_remap = Some(RemapConfigBuilder::new(key_def));
+                current_remap = Some(RemapConfigBuilder::new(key_def, value_span()));
             }
             "when_alone" => {
-                let key_def = find_key_by_name(value)
-                    .ok_or_else(|| format!("Config error (line {}): invalid key name '{}'", line_num, value))?;
+                let sequence = parse_sequence(value)
+                    .map_err(|e| value_error(line_num, raw_line, value, value_start, e))?;
                 
                 if let Some(ref mut builder) = current_remap {
-                    builder.when_alone = Some(key_def);
+                    builder.when_alone = Some(sequence);
                 } else {
-                    return Err(format!("Config error (line {}): when_alone must come after remap_key", line_num));
+                    return Err(orphan("when_alone", "remap_key"));
                 }
             }
             "with_other" => {
-                let key_def = find_key_by_name(value)
-                    .ok_or_else(|| format!("Config error (line {}): invalid key name '{}'", line_num, value))?;
+                let with_other = parse_with_other(value)
+                    .map_err(|e| value_error(line_num, raw_line, value, value_start, e))?;
+                
+                if let Some(ref mut builder) = current_remap {
+                    builder.with_other = Some(with_other);
+                } else {
+                    return Err(orphan("with_other", "remap_key"));
+                }
+            }
+            "hold_timeout_ms" => {
+                let timeout = value.parse::<u64>()
+                    .map_err(|_| ConfigError::InvalidValue {
+                        span: value_span(),
+                        message: format!("invalid hold_timeout_ms '{}', expected milliseconds", value),
+                    })?;
+                
+                // remap_key 之后的设置属于该 remap，之前的为全局默认值
+                if let Some(ref mut builder) = current_remap {
+                    builder.hold_timeout_ms = Some(timeout);
+                } else {
+                    config.hold_timeout_ms = Some(timeout);
+                }
+            }
+            "hold_mode" => {
+                let hold_mode = HoldMode::parse(value)
+                    .ok_or_else(|| ConfigError::InvalidValue {
+                        span: value_span(),
+                        message: format!("invalid hold_mode '{}', expected hold_on_other_press, permissive_hold or balanced", value),
+                    })?;
+                
+                if let Some(ref mut builder) = current_remap {
+                    builder.hold_mode = Some(hold_mode);
+                } else {
+                    config.hold_mode = Some(hold_mode);
+                }
+            }
+            "quick_tap_ms" => {
+                let quick_tap = value.parse::<u64>()
+                    .map_err(|_| ConfigError::InvalidValue {
+                        span: value_span(),
+                        message: format!("invalid quick_tap_ms '{}', expected milliseconds", value),
+                    })?;
+                
+                if let Some(ref mut builder) = current_remap {
+                    builder.quick_tap_ms = Some(quick_tap);
+                } else {
+                    config.quick_tap_ms = Some(quick_tap);
+                }
+            }
+            "retro_tap" => {
+                let retro_tap = parse_bool(value)
+                    .ok_or_else(|| ConfigError::InvalidValue {
+                        span: value_span(),
+                        message: format!("invalid retro_tap '{}', expected true or false", value),
+                    })?;
                 
                 if let Some(ref mut builder) = current_remap {
-                    builder.with_other = Some(key_def);
+                    builder.retro_tap = Some(retro_tap);
+                } else {
+                    config.retro_tap = Some(retro_tap);
+                }
+            }
+            "device" => {
+                config.device = Some(value.to_string());
+            }
+            "match_by" => {
+                config.match_by = MatchBy::parse(value)
+                    .ok_or_else(|| ConfigError::InvalidValue {
+                        span: value_span(),
+                        message: format!("invalid match_by '{}', expected virtual_key or scan_code", value),
+                    })?;
+            }
+            "foreign_injected" => {
+                config.foreign_injected = ForeignInjected::parse(value)
+                    .ok_or_else(|| ConfigError::InvalidValue {
+                        span: value_span(),
+                        message: format!("invalid foreign_injected '{}', expected ignore, other or remap", value),
+                    })?;
+            }
+            "log_file" => {
+                config.log_file = Some(value.to_string());
+            }
+            "debug" | "strict" => {
+                let enabled = parse_bool(value)
+                    .ok_or_else(|| ConfigError::InvalidValue {
+                        span: value_span(),
+                        message: format!("invalid {} '{}', expected true or false", key, value),
+                    })?;
+                
+                if key == "debug" {
+                    config.debug = enabled;
                 } else {
-                    return Err(format!("Config error (line {}): with_other must come after remap_key", line_num));
+                    config.strict = enabled;
+                }
+            }
+            "layer" => {
+                finish_remap(&mut config, current_remap.take())
+                    .map_err(|e| explain_incomplete(e, &mut unknown))?;
+                
+                if value.is_empty() {
+                    return Err(ConfigError::InvalidValue {
+                        span: value_span(),
+                        message: "layer needs a name".to_string(),
+                    });
+                }
+                if config.layers.iter().any(|layer| layer.name == value) {
+                    return Err(ConfigError::DuplicateLayer { span: value_span(), name: value.to_string() });
+                }
+                
+                config.layers.push(LayerConfig { name: value.to_string(), mappings: Vec::new() });
+                current_layer = Some(config.layers.len() - 1);
+            }
+            "map" => {
+                let (from, to) = value.split_once("->")
+                    .ok_or_else(|| ConfigError::Syntax {
+                        span: value_span(),
+                        message: "expected map=FROM->TO".to_string(),
+                    })?;
+                let mapping = parse_mapping(from, to)
+                    .map_err(|e| value_error(line_num, raw_line, value, value_start, e))?;
+                
+                match current_layer {
+                    Some(index) => config.layers[index].mappings.push(mapping),
+                    None => return Err(orphan("map", "layer")),
                 }
             }
             _ => {
-                // 忽略其他设置（如debug等）
-                continue;
+                // 未知设置先记下，strict 时在最后报错，否则作为警告
+                unknown.push(ConfigError::UnknownSetting {
+                    span: Span::at(line_num, raw_line, line_start, key.len()),
+                    name: key.to_string(),
+                    suggestion: suggest(key, SETTINGS.iter().copied()),
+                });
+            }
+        }
+    }
+    
+    // 处理最后一个remap
+    finish_remap(&mut config, current_remap)
+        .map_err(|e| explain_incomplete(e, &mut unknown))?;
+    
+    if config.strict && !unknown.is_empty() {
+        return Err(unknown.remove(0));
+    }
+    config.warnings = unknown;
+    
+    apply_defaults(&mut config);
+    check_layers(&config)?;
+    validate(&mut config)?;
+    Ok(config)
+}
+
+// 结束当前的 remap：完整则加入配置，否则报错
+fn finish_remap(config: &mut Config, builder: Option<RemapConfigBuilder>) -> Result<(), ConfigError> {
+    if let Some(builder) = builder {
+        config.remaps.push(builder.build()?);
+    }
+    Ok(())
+}
+
+// remap 缺少的设置如果是被拼错了，报告拼错的那一行更有用
+fn explain_incomplete(err: ConfigError, unknown: &mut Vec<ConfigError>) -> ConfigError {
+    let ConfigError::IncompleteRemap { ref missing, .. } = err else {
+        return err;
+    };
+    
+    let misspelled = unknown.iter().position(|warning| {
+        matches!(warning, ConfigError::UnknownSetting { suggestion: Some(suggestion), .. } if missing.contains(suggestion))
+    });
+    match misspelled {
+        Some(index) => unknown.remove(index),
+        None => err,
+    }
+}
+
+// 检查 with_other 引用的层都已定义
+fn check_layers(config: &Config) -> Result<(), ConfigError> {
+    for remap in &config.remaps {
+        if let WithOther::Layer(ref name) = remap.to_with_other {
+            if !config.layers.iter().any(|layer| &layer.name == name) {
+                return Err(ConfigError::UndefinedLayer { key: remap.from.name.to_string(), layer: name.clone() });
             }
         }
+    }
+    Ok(())
+}
+
+// 检查无法同时生效的 remap 和层映射：无法匹配或重复的键、通用与区分左右的修饰键重叠、
+// 输出后端无法发送的键；互相输出对方的键只作为警告
+fn validate(config: &mut Config) -> Result<(), ConfigError> {
+    let key_id = |key_def: &KeyDef| config.match_by.key_id(key_def);
+    let mut cycles = Vec::new();
+    
+    for (i, remap) in config.remaps.iter().enumerate() {
+        let from = remap.from.virt_code;
+        
+        if key_id(&remap.from) == 0 {
+            return Err(ConfigError::UnmatchableKey { key: remap.from.name.to_string(), match_by: config.match_by });
+        }
         
-        // 检查当前remap是否完整，如果是则添加到配置中
-        if let Some(ref builder) = current_remap {
-            if builder.is_complete() {
-                config.remaps.push(current_remap.take().unwrap().build()?);
+        for other in &config.remaps[..i] {
+            if key_id(&other.from) == key_id(&remap.from) {
+                return Err(ConfigError::DuplicateRemapKey {
+                    span: None,
+                    key: remap.from.name.to_string(),
+                    first_line: None,
+                });
             }
+            
+            let overlap = generic_modifier(from) == Some(other.from.virt_code)
+                || generic_modifier(other.from.virt_code) == Some(from);
+            if overlap {
+                let (generic, sided) = if generic_modifier(from).is_some() { (other, remap) } else { (remap, other) };
+                return Err(ConfigError::ModifierOverlap {
+                    generic: generic.from.name.to_string(),
+                    sided: sided.from.name.to_string(),
+                });
+            }
+        }
+        
+        // 输出自己（如 LCTRL 单独按下为 ESCAPE、组合时仍为 LCTRL）不提示
+        for other in &config.remaps[..i] {
+            let (first, second) = if sends_as_with_other(remap, other.from) && sends(other, remap.from) {
+                (remap, other)
+            } else if sends_as_with_other(other, remap.from) && sends(remap, other.from) {
+                (other, remap)
+            } else {
+                continue;
+            };
+            cycles.push(ConfigError::RemapCycle {
+                first: first.from.name.to_string(),
+                second: second.from.name.to_string(),
+            });
         }
     }
     
-    // 处理最后一个remap
-    if let Some(builder) = current_remap {
-        if builder.is_complete() {
-            config.remaps.push(builder.build()?);
-        } else {
-            return Err("Config error: Incomplete remapping at end of file".to_string());
+    // 层映射的源键同样按 match_by 匹配，键码为 0 的键会匹配所有未知按键
+    for layer in &config.layers {
+        for (i, (from, _)) in layer.mappings.iter().enumerate() {
+            if key_id(from) == 0 {
+                return Err(ConfigError::UnmatchableKey { key: from.name.to_string(), match_by: config.match_by });
+            }
+            if layer.mappings[..i].iter().any(|(other, _)| key_id(other) == key_id(from)) {
+                return Err(ConfigError::DuplicateLayerMapping { layer: layer.name.clone(), key: from.name.to_string() });
+            }
         }
     }
     
-    Ok(config)
+    if let Some(key_def) = output_keys(config).find(|key_def| !can_send(key_def)) {
+        return Err(ConfigError::UnsendableKey { key: key_def.name.to_string() });
+    }
+    
+    config.warnings.extend(cycles);
+    Ok(())
+}
+
+// remap 和层映射可能发送的所有键，文本按美式布局输入，总能发送
+fn output_keys(config: &Config) -> impl Iterator<Item = &KeyDef> {
+    let remap_keys = config.remaps.iter().flat_map(|remap| {
+        let when_alone = remap.to_when_alone.0.iter().flat_map(|stroke| match stroke {
+            Stroke::Chord(keys) => keys.as_slice(),
+            Stroke::Text(_) => &[],
+        });
+        let with_other = match remap.to_with_other {
+            WithOther::Chord(ref keys) => keys.as_slice(),
+            WithOther::Layer(_) => &[],
+        };
+        when_alone.chain(with_other)
+    });
+    let layer_keys = config.layers.iter().flat_map(|layer| layer.mappings.iter().map(|(_, to)| to));
+    remap_keys.chain(layer_keys)
+}
+
+fn sends_as_with_other(remap: &RemapConfig, key: KeyDef) -> bool {
+    match remap.to_with_other {
+        WithOther::Chord(ref keys) => keys.iter().any(|key_def| key_def.virt_code == key.virt_code),
+        WithOther::Layer(_) => false,
+    }
+}
+
+fn sends(remap: &RemapConfig, key: KeyDef) -> bool {
+    let when_alone = remap.to_when_alone.0.iter().any(|stroke| match stroke {
+        Stroke::Chord(keys) => keys.iter().any(|key_def| key_def.virt_code == key.virt_code),
+        Stroke::Text(_) => false,
+    });
+    when_alone || sends_as_with_other(remap, key)
+}
+
+// 未单独设置的 remap 使用全局默认值
+fn apply_defaults(config: &mut Config) {
+    for remap in &mut config.remaps {
+        remap.hold_timeout_ms = remap.hold_timeout_ms.or(config.hold_timeout_ms);
+        remap.hold_mode = remap.hold_mode.or(config.hold_mode);
+        remap.quick_tap_ms = remap.quick_tap_ms.or(config.quick_tap_ms);
+        remap.retro_tap = remap.retro_tap.or(config.retro_tap);
+    }
 }
 
 #[derive(Debug)]
 struct RemapConfigBuilder {
     from: KeyDef,
-    when_alone: Option<KeyDef>,
-    with_other: Option<KeyDef>,
+    // remap_key 的位置，用于报告不完整的 remap
+    span: Span,
+    when_alone: Option<Sequence>,
+    with_other: Option<WithOther>,
+    hold_timeout_ms: Option<u64>,
+    hold_mode: Option<HoldMode>,
+    quick_tap_ms: Option<u64>,
+    retro_tap: Option<bool>,
 }
 
 impl RemapConfigBuilder {
-    fn new(from: KeyDef) -> Self {
+    fn new(from: KeyDef, span: Span) -> Self {
         Self {
             from,
+            span,
             when_alone: None,
             with_other: None,
+            hold_timeout_ms: None,
+            hold_mode: None,
+            quick_tap_ms: None,
+            retro_tap: None,
         }
     }
     
-    fn is_complete(&self) -> bool {
-        self.when_alone.is_some() && self.with_other.is_some()
+    fn build(self) -> Result<RemapConfig, ConfigError> {
+        match (self.when_alone, self.with_other) {
+            (Some(when_alone), Some(with_other)) => Ok(RemapConfig {
+                from: self.from,
+                to_when_alone: when_alone,
+                to_with_other: with_other,
+                hold_timeout_ms: self.hold_timeout_ms,
+                hold_mode: self.hold_mode,
+                quick_tap_ms: self.quick_tap_ms,
+                retro_tap: self.retro_tap,
+            }),
+            (when_alone, with_other) => {
+                let mut missing = Vec::new();
+                if when_alone.is_none() {
+                    missing.push("when_alone");
+                }
+                if with_other.is_none() {
+                    missing.push("with_other");
+                }
+                Err(ConfigError::IncompleteRemap { span: self.span, key: self.from.name.to_string(), missing })
+            }
+        }
     }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::keys::find_key_by_name;
     
-    fn build(self) -> Result<RemapConfig, String> {
-        Ok(RemapConfig {
-            from: self.from,
-            to_when_alone: self.when_alone.ok_or("Missing when_alone")?,
-            to_with_other: self.with_other.ok_or("Missing with_other")?,
-        })
+    #[test]
+    fn toml_and_legacy_formats_agree() {
+        let legacy = parse_config("hold_timeout_ms=200\nremap_key=CAPSLOCK\nwhen_alone=ESCAPE\nwith_other=CTRL\n").unwrap();
+        let toml = parse_toml_config(
+            "hold_timeout_ms = 200\n\n[[remap]]\nremap_key = \"CAPSLOCK\"\nwhen_alone = \"ESCAPE\"\nwith_other = \"CTRL\"\n",
+        ).unwrap();
+        
+        assert_eq!(toml.remaps.len(), 1);
+        assert_eq!(toml.remaps[0].from, legacy.remaps[0].from);
+        assert_eq!(toml.remaps[0].to_when_alone, legacy.remaps[0].to_when_alone);
+        assert_eq!(toml.remaps[0].to_with_other, legacy.remaps[0].to_with_other);
+        assert_eq!(toml.remaps[0].to_with_other, WithOther::Chord(vec![find_key_by_name("CTRL").unwrap()]));
+        assert_eq!(toml.remaps[0].hold_timeout_ms, Some(200));
+    }
+    
+    #[test]
+    fn toml_per_remap_options_override_globals() {
+        let config = parse_toml_config(
+            "hold_timeout_ms = 200\nquick_tap_ms = 150\ndevice = \"AT Translated\"\n\n\
+             [[remap]]\nremap_key = \"CAPSLOCK\"\nwhen_alone = \"ESCAPE\"\nwith_other = \"CTRL\"\nhold_timeout_ms = 500\nquick_tap_ms = 0\n\n\
+             [[remap]]\nremap_key = \"SPACE\"\nwhen_alone = \"SPACE\"\nwith_other = \"SHIFT\"\nretro_tap = true\n",
+        ).unwrap();
+        
+        assert_eq!(config.device.as_deref(), Some("AT Translated"));
+        assert_eq!(config.remaps[0].hold_timeout_ms, Some(500));
+        assert_eq!(config.remaps[1].hold_timeout_ms, Some(200));
+        assert_eq!(config.remaps[0].quick_tap_ms, Some(0));
+        assert_eq!(config.remaps[1].quick_tap_ms, Some(150));
+        assert_eq!(config.remaps[0].retro_tap, None);
+        assert_eq!(config.remaps[1].retro_tap, Some(true));
+    }
+    
+    #[test]
+    fn hold_mode_defaults() {
+        let legacy = parse_config(
+            "hold_mode=permissive_hold\n\
+             remap_key=CAPSLOCK\nwhen_alone=ESCAPE\nwith_other=CTRL\nhold_mode=hold_on_other_press\n\
+             remap_key=SPACE\nwhen_alone=SPACE\nwith_other=SHIFT\n",
+        ).unwrap();
+        let toml = parse_toml_config(
+            "hold_mode = \"permissive_hold\"\n\n\
+             [[remap]]\nremap_key = \"CAPSLOCK\"\nwhen_alone = \"ESCAPE\"\nwith_other = \"CTRL\"\nhold_mode = \"hold_on_other_press\"\n\n\
+             [[remap]]\nremap_key = \"SPACE\"\nwhen_alone = \"SPACE\"\nwith_other = \"SHIFT\"\n",
+        ).unwrap();
+        
+        for config in [legacy, toml] {
+            assert_eq!(config.remaps[0].hold_mode, Some(HoldMode::HoldOnOtherPress));
+            assert_eq!(config.remaps[1].hold_mode, Some(HoldMode::PermissiveHold));
+        }
+        
+        assert!(parse_config("hold_mode=sometimes\n").is_err());
+    }
+    
+    #[test]
+    fn layers_in_both_formats() {
+        let legacy = parse_config(
+            "remap_key=CAPSLOCK\nwhen_alone=ESCAPE\nwith_other=layer:nav\nlayer=nav\nmap=H->LEFT\nmap=L -> RIGHT\n",
+        ).unwrap();
+        let toml = parse_toml_config(
+            "[[remap]]\nremap_key = \"CAPSLOCK\"\nwhen_alone = \"ESCAPE\"\nwith_other = \"layer:nav\"\n\n\
+             [layers.nav]\nH = \"LEFT\"\nL = \"RIGHT\"\n",
+        ).unwrap();
+        
+        for config in [legacy, toml] {
+            assert_eq!(config.remaps[0].to_with_other, WithOther::Layer("nav".to_string()));
+            assert_eq!(config.layers.len(), 1);
+            assert_eq!(config.layers[0].name, "nav");
+            assert_eq!(config.layers[0].mappings, vec![
+                (find_key_by_name("H").unwrap(), find_key_by_name("LEFT").unwrap()),
+                (find_key_by_name("L").unwrap(), find_key_by_name("RIGHT").unwrap()),
+            ]);
+        }
+    }
+    
+    #[test]
+    fn undefined_layer_is_an_error() {
+        let err = parse_config("remap_key=CAPSLOCK\nwhen_alone=ESCAPE\nwith_other=layer:nav\n").unwrap_err();
+        assert!(err.to_string().contains("undefined layer 'nav'"), "{}", err);
+        
+        let err = parse_config("map=H->LEFT\n").unwrap_err();
+        assert!(err.to_string().contains("map must come after layer"), "{}", err);
+    }
+    
+    #[test]
+    fn raw_key_codes_in_config() {
+        let config = parse_config("remap_key=vk:0xE2\nwhen_alone=sc:0xE05B\nwith_other=vk:0xE1 sc:0x73\n").unwrap();
+        
+        assert_eq!(config.remaps[0].from, find_key_by_name("OEM_102").unwrap());
+        assert_eq!(config.remaps[0].to_when_alone, Sequence(vec![Stroke::Chord(vec![find_key_by_name("LWIN").unwrap()])]));
+        match config.remaps[0].to_with_other {
+            WithOther::Chord(ref keys) => assert_eq!((keys[0].virt_code, keys[0].scan_code), (0xE1, 0x73)),
+            ref other => panic!("unexpected with_other {}", other),
+        }
+        
+        let err = parse_config("remap_key=vk:0xGG\n").unwrap_err();
+        assert!(err.to_string().contains("vk:0x.. / sc:0x.."), "{}", err);
+        
+        // Linux 下只能发送有对应 KEY_* 码的扫描码，只有虚拟键码的键无法发送
+        #[cfg(target_os = "linux")]
+        {
+            let err = parse_config("remap_key=CAPSLOCK\nwhen_alone=sc:0x5A\nwith_other=CTRL\n").unwrap_err();
+            assert_eq!(err.message(), "sc:0x5A cannot be sent on this platform");
+            let err = parse_config("layer=nav\nmap=H->vk:0xE1\n").unwrap_err();
+            assert_eq!(err.message(), "vk:0xE1 cannot be sent on this platform");
+        }
+    }
+    
+    #[test]
+    fn when_alone_sequences() {
+        let ctrl = find_key_by_name("CTRL").unwrap();
+        let s = find_key_by_name("S").unwrap();
+        let escape = find_key_by_name("ESCAPE").unwrap();
+        
+        assert_eq!(parse_sequence("CTRL+C").unwrap().0.len(), 1);
+        assert_eq!(parse_sequence("ESCAPE, CTRL+S").unwrap(), Sequence(vec![
+            Stroke::Chord(vec![escape]),
+            Stroke::Chord(vec![ctrl, s]),
+        ]));
+        assert_eq!(parse_sequence("\"a, b\", ENTER").unwrap(), Sequence(vec![
+            Stroke::Text("a, b".to_string()),
+            Stroke::Chord(vec![find_key_by_name("ENTER").unwrap()]),
+        ]));
+        assert_eq!(parse_sequence("ESCAPE, CTRL+S").unwrap().to_string(), "ESCAPE, CTRL+S");
+        
+        assert!(parse_sequence("\"open").is_err());
+        assert!(parse_sequence("CTRL+").is_err());
+        assert!(parse_sequence("ESCAPE,").is_err());
+        
+        let config = parse_toml_config(
+            "[[remap]]\nremap_key = \"CAPSLOCK\"\nwhen_alone = '\"hi\", ESCAPE'\nwith_other = \"CTRL\"\n",
+        ).unwrap();
+        assert_eq!(config.remaps[0].to_when_alone.to_string(), "\"hi\", ESCAPE");
+    }
+    
+    #[test]
+    fn with_other_chords() {
+        let config = parse_config("remap_key=CAPSLOCK\nwhen_alone=ESCAPE\nwith_other=CTRL + ALT+SHIFT+LWIN\n").unwrap();
+        let names: Vec<&str> = match config.remaps[0].to_with_other {
+            WithOther::Chord(ref keys) => keys.iter().map(|key_def| key_def.name).collect(),
+            ref other => panic!("unexpected with_other {}", other),
+        };
+        assert_eq!(names, ["CTRL", "ALT", "SHIFT", "LWIN"]);
+        assert_eq!(config.remaps[0].to_with_other.to_string(), "CTRL+ALT+SHIFT+LWIN");
+        
+        assert!(parse_config("remap_key=CAPSLOCK\nwhen_alone=ESCAPE\nwith_other=CTRL+\n").is_err());
+    }
+    
+    #[test]
+    fn unknown_settings_are_warnings() {
+        let config = parse_config("debug=1\nfoo=bar\nremap_key=CAPSLOCK\nwhen_alone=ESCAPE\nwith_other=CTRL\nhold_mod=balanced\n").unwrap();
+        assert!(config.debug);
+        assert_eq!(config.remaps.len(), 1);
+        
+        let warnings: Vec<String> = config.warnings.iter().map(|warning| warning.message()).collect();
+        assert_eq!(warnings, ["unknown setting 'foo'", "unknown setting 'hold_mod', did you mean hold_mode?"]);
+        
+        let err = parse_config("foo=bar\nremap_key=CAPSLOCK\nwhen_alone=ESCAPE\nwith_other=CTRL\nstrict=true\n").unwrap_err();
+        assert!(matches!(err, ConfigError::UnknownSetting { ref span, .. } if span.line == 1), "{:?}", err);
+        
+        // 拼错的设置导致 remap 不完整时报告拼错的那一行
+        let err = parse_config("remap_key=CAPSLOCK\nwhen_alon=ESCAPE\nwith_other=CTRL\n").unwrap_err();
+        assert_eq!(err.message(), "unknown setting 'when_alon', did you mean when_alone?");
+        
+        let config = parse_toml_config("debug = true\nstrict = true\n").unwrap();
+        assert!(config.debug && config.strict);
+        
+        let toml = "debugg = true\n\n[[remap]]\nremap_key = \"CAPSLOCK\"\nwhen_alone = \"ESCAPE\"\n\
+                    with_other = \"CTRL\"\nhold_mod = \"balanced\"\n\n[layers.nav]\nH = \"LEFT\"\n";
+        let config = parse_toml_config(toml).unwrap();
+        assert_eq!(config.remaps.len(), 1);
+        let warnings: Vec<String> = config.warnings.iter().map(|warning| warning.message()).collect();
+        assert_eq!(warnings, ["unknown setting 'debugg', did you mean debug?", "unknown setting 'hold_mod', did you mean hold_mode?"]);
+        assert!(matches!(config.warnings[1], ConfigError::UnknownSetting { ref span, .. } if span.line == 7 && span.column == 1));
+        
+        let err = parse_toml_config(&format!("strict = true\n{}", toml)).unwrap_err();
+        assert!(matches!(err, ConfigError::UnknownSetting { ref span, ref name, .. } if span.line == 2 && name == "debugg"), "{:?}", err);
+    }
+    
+    #[test]
+    fn conflicting_remaps_are_rejected() {
+        const CAPS: &str = "[[remap]]\nremap_key = \"CAPSLOCK\"\nwhen_alone = \"ESCAPE\"\nwith_other = \"CTRL\"\n";
+        
+        let err = parse_toml_config(&format!("{}{}", CAPS, CAPS)).unwrap_err();
+        assert!(matches!(err, ConfigError::DuplicateRemapKey { ref key, .. } if key == "CAPSLOCK"), "{:?}", err);
+        
+        let err = parse_config(
+            "remap_key=LCTRL\nwhen_alone=ESCAPE\nwith_other=LCTRL\n\
+             remap_key=CTRL\nwhen_alone=TAB\nwith_other=CTRL\n",
+        ).unwrap_err();
+        assert_eq!(err.message(), "both CTRL and LCTRL are remapped; remap either the generic or the left/right modifier");
+        
+        // 互相输出对方只是警告，自己发送的按键不会再被重映射
+        let config = parse_config(
+            "remap_key=CAPSLOCK\nwhen_alone=ESCAPE\nwith_other=TAB\n\
+             remap_key=TAB\nwhen_alone=CAPSLOCK\nwith_other=LALT\n",
+        ).unwrap();
+        assert!(matches!(config.warnings[..], [ConfigError::RemapCycle { ref first, ref second }] if first == "CAPSLOCK" && second == "TAB"), "{:?}", config.warnings);
+        
+        // 交换 CAPSLOCK 和 LCTRL
+        let config = parse_config(
+            "remap_key=CAPSLOCK\nwhen_alone=ESCAPE\nwith_other=LCTRL\n\
+             remap_key=LCTRL\nwhen_alone=CAPSLOCK\nwith_other=LCTRL\n",
+        ).unwrap();
+        assert_eq!(config.remaps.len(), 2);
+        assert_eq!(config.warnings.len(), 1);
+        
+        // 输出自己、或单独按下时输出另一个 remap 的键都是允许的
+        let config = parse_config(
+            "remap_key=SPACE\nwhen_alone=SPACE\nwith_other=LSHIFT\n\
+             remap_key=CAPSLOCK\nwhen_alone=SPACE\nwith_other=RCTRL\n\
+             remap_key=LCTRL\nwhen_alone=ESCAPE\nwith_other=LCTRL\n",
+        ).unwrap();
+        assert_eq!(config.remaps.len(), 3);
+    }
+    
+    #[test]
+    fn match_by_scan_code() {
+        let config = parse_config(
+            "match_by=scan_code\n\
+             remap_key=ENTER\nwhen_alone=ENTER\nwith_other=RCTRL\n\
+             remap_key=NUMPAD_ENTER\nwhen_alone=TAB\nwith_other=LALT\n",
+        ).unwrap();
+        assert_eq!(config.match_by, MatchBy::ScanCode);
+        
+        // 按虚拟键码两者相同
+        let err = parse_config(
+            "remap_key=ENTER\nwhen_alone=ENTER\nwith_other=RCTRL\n\
+             remap_key=NUMPAD_ENTER\nwhen_alone=TAB\nwith_other=LALT\n",
+        ).unwrap_err();
+        assert!(matches!(err, ConfigError::DuplicateRemapKey { ref key, .. } if key == "NUMPAD_ENTER"), "{:?}", err);
+        
+        let err = parse_config("match_by=scan_code\nremap_key=vk:0xE1\nwhen_alone=ESCAPE\nwith_other=CTRL\n").unwrap_err();
+        assert_eq!(err.message(), "vk:0xE1 has no scan code, so it cannot be matched with match_by=scan_code");
+        
+        // 层映射的源键同样需要能够匹配，且在一层中不能重复
+        let err = parse_config("layer=nav\nmap=sc:0x7D->LEFT\nmap=sc:0x73->RIGHT\n").unwrap_err();
+        assert_eq!(err.message(), "sc:0x7D has no virtual key code, so it cannot be matched with match_by=virtual_key");
+        let config = parse_config("match_by=scan_code\nlayer=nav\nmap=sc:0x7D->LEFT\nmap=sc:0x73->RIGHT\n").unwrap();
+        assert_eq!(config.layers[0].mappings.len(), 2);
+        let err = parse_config("layer=nav\nmap=ENTER->LEFT\nmap=NUMPAD_ENTER->RIGHT\n").unwrap_err();
+        assert_eq!(err.message(), "NUMPAD_ENTER is mapped twice in layer 'nav'");
+        let err = parse_toml_config("[layers.nav]\nESC = \"LEFT\"\nESCAPE = \"RIGHT\"\n").unwrap_err();
+        assert!(matches!(err, ConfigError::DuplicateLayerMapping { ref layer, .. } if layer == "nav"), "{:?}", err);
+        
+        let config = parse_toml_config("match_by = \"scan_code\"\n").unwrap();
+        assert_eq!(config.match_by, MatchBy::ScanCode);
+        assert!(parse_config("match_by=layout\n").is_err());
+    }
+    
+    #[test]
+    fn toml_rejects_unknown_key_names() {
+        let err = parse_toml_config("[[remap]]\nremap_key = \"CAPSLOK\"\nwhen_alone = \"ESCAPE\"\nwith_other = \"CTRL\"\n")
+            .unwrap_err();
+        assert!(err.to_string().contains("invalid key name 'CAPSLOK', did you mean CAPSLOCK?"), "{}", err);
     }
 }
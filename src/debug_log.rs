@@ -0,0 +1,146 @@
+use crate::input::{Direction, InputSource, OutputSink};
+use crate::keys::{key_for_codes, KeyDef};
+use std::fmt;
+use std::fs::OpenOptions;
+use std::io::{self, Write};
+
+// debug 模式的日志：每行带上 RemapManager 的毫秒时间戳
+pub struct DebugLog {
+    writer: Box<dyn Write + Send>,
+    time: u64,
+}
+
+impl DebugLog {
+    pub fn new(writer: Box<dyn Write + Send>) -> Self {
+        Self { writer, time: 0 }
+    }
+    
+    // 有 log_file 时追加写入该文件，否则输出到控制台
+    pub fn open(log_file: Option<&str>) -> Result<Self, String> {
+        match log_file {
+            Some(path) => {
+                let file = OpenOptions::new()
+                    .create(true)
+                    .append(true)
+                    .open(path)
+                    .map_err(|e| format!("Cannot open log file '{}': {}", path, e))?;
+                Ok(Self::new(Box::new(file)))
+            }
+            None => Ok(Self::new(Box::new(io::stdout()))),
+        }
+    }
+    
+    pub fn set_time(&mut self, time: u64) {
+        self.time = time;
+    }
+    
+    pub fn write(&mut self, message: fmt::Arguments) {
+        let _ = writeln!(self.writer, "[{:>10} ms] {}", self.time, message);
+        let _ = self.writer.flush();
+    }
+    
+    pub fn input(&mut self, virt_code: u32, scan_code: u32, direction: Direction, source: InputSource) {
+        let key_def = key_for_codes(virt_code, scan_code);
+        self.write(format_args!(
+            "input  {} vk=0x{:02X} sc=0x{:02X} {}{}",
+            key_def.name,
+            virt_code,
+            scan_code,
+            direction_name(direction),
+            match source {
+                InputSource::Physical => "",
+                InputSource::Own => " (injected, own)",
+                InputSource::Foreign => " (injected, foreign)",
+            },
+        ));
+    }
+}
+
+fn direction_name(direction: Direction) -> &'static str {
+    match direction {
+        Direction::Down => "down",
+        Direction::Up => "up",
+    }
+}
+
+// 包装输出后端，debug 模式下记录每个输出
+pub struct LoggedSink<S: OutputSink> {
+    pub sink: S,
+    pub log: Option<DebugLog>,
+}
+
+impl<S: OutputSink> OutputSink for LoggedSink<S> {
+    fn send_key(&mut self, key_def: &KeyDef, direction: Direction) -> Result<(), String> {
+        if let Some(ref mut log) = self.log {
+            log.write(format_args!("output {} {}", key_def.name, direction_name(direction)));
+        }
+        self.sink.send_key(key_def, direction)
+    }
+    
+    fn replay_key(&mut self, virt_code: u32, scan_code: u32, direction: Direction) -> Result<(), String> {
+        if let Some(ref mut log) = self.log {
+            let key_def = key_for_codes(virt_code, scan_code);
+            log.write(format_args!("replay {} {}", key_def.name, direction_name(direction)));
+        }
+        self.sink.replay_key(virt_code, scan_code, direction)
+    }
+    
+    fn send_text(&mut self, text: &str) -> Result<(), String> {
+        if let Some(ref mut log) = self.log {
+            log.write(format_args!("output text {:?}", text));
+        }
+        self.sink.send_text(text)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::parse_config;
+    use crate::input::RecordingSink;
+    use crate::remap::RemapManager;
+    use crate::replay::vk;
+    use std::sync::{Arc, Mutex};
+    
+    #[derive(Clone, Default)]
+    struct SharedBuffer(Arc<Mutex<Vec<u8>>>);
+    
+    impl Write for SharedBuffer {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+        
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+    
+    #[test]
+    fn logs_inputs_state_transitions_and_outputs() {
+        let config = parse_config("debug=1\nremap_key=CAPSLOCK\nwhen_alone=ESCAPE\nwith_other=CTRL\n").unwrap();
+        assert!(config.debug);
+        
+        let buffer = SharedBuffer::default();
+        let mut manager = RemapManager::new(config, RecordingSink::default());
+        manager.set_log(Some(DebugLog::new(Box::new(buffer.clone()))));
+        
+        manager.handle_input(vk("CAPSLOCK"), 0x3A, Direction::Down, InputSource::Physical, 5);
+        manager.handle_input(vk("A"), 0x1E, Direction::Down, InputSource::Physical, 20);
+        manager.handle_input(vk("CAPSLOCK"), 0x3A, Direction::Up, InputSource::Physical, 40);
+        manager.handle_input(vk("LCTRL"), 0x1D, Direction::Up, InputSource::Own, 41);
+        
+        let log = String::from_utf8(buffer.0.lock().unwrap().clone()).unwrap();
+        let lines: Vec<&str> = log.lines().collect();
+        assert_eq!(lines, [
+            "[         5 ms] input  CAPSLOCK vk=0x14 sc=0x3A down",
+            "[         5 ms] state  CAPSLOCK Idle -> HeldDownAlone",
+            "[        20 ms] input  A vk=0x41 sc=0x1E down",
+            "[        20 ms] state  CAPSLOCK HeldDownAlone -> HeldDownWithOther",
+            "[        20 ms] output CTRL down",
+            "[        40 ms] input  CAPSLOCK vk=0x14 sc=0x3A up",
+            "[        40 ms] state  CAPSLOCK HeldDownWithOther -> Idle",
+            "[        40 ms] output CTRL up",
+            "[        41 ms] input  LCTRL vk=0xA2 sc=0x1D up (injected, own)",
+        ]);
+    }
+}
@@ -0,0 +1,302 @@
+use crate::config::MatchBy;
+use crate::keys::{KEYS, KEY_ALIASES};
+use std::fmt;
+
+// 出错位置：行号和列号从 1 开始，列号按字符计；text 为整行原文，用于显示出错的片段
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Span {
+    pub line: usize,
+    pub column: usize,
+    pub len: usize,
+    pub text: String,
+}
+
+impl Span {
+    // start 和 len 是出错片段在 line 中的字节位置和长度，超出行尾的部分（如跨行的 TOML 值）不计
+    pub fn at(line_num: usize, line: &str, start: usize, len: usize) -> Self {
+        let start = start.min(line.len());
+        let end = (start + len).min(line.len());
+        
+        Self {
+            line: line_num,
+            column: line[..start].chars().count() + 1,
+            len: line[start..end].chars().count().max(1),
+            text: line.to_string(),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum ConfigError {
+    Io { path: String, message: String },
+    Syntax { span: Span, message: String },
+    UnknownKey { span: Option<Span>, name: String, suggestion: Option<&'static str> },
+    UnknownSetting { span: Span, name: String, suggestion: Option<&'static str> },
+    InvalidValue { span: Span, message: String },
+    // 例如出现在 remap_key 之前的 when_alone
+    OrphanSetting { span: Span, setting: &'static str, parent: &'static str },
+    IncompleteRemap { span: Span, key: String, missing: Vec<&'static str> },
+    DuplicateRemapKey { span: Option<Span>, key: String, first_line: Option<usize> },
+    DuplicateLayer { span: Span, name: String },
+    // 同一层中两个映射的源键在 match_by 方式下相同
+    DuplicateLayerMapping { layer: String, key: String },
+    UndefinedLayer { key: String, layer: String },
+    // first 的 with_other 输出 second，而 second 又输出 first。自己发送的按键不会再被重映射，
+    // 所以只作为警告，例如交换 CAPSLOCK 和 LCTRL 是允许的
+    RemapCycle { first: String, second: String },
+    // 同时重映射了通用修饰键和区分左右的修饰键，如 CTRL 和 LCTRL
+    ModifierOverlap { generic: String, sided: String },
+    // 在 match_by 方式下没有键码的键，如 match_by=scan_code 时的 vk:0x..
+    UnmatchableKey { key: String, match_by: MatchBy },
+    // 输出后端无法发送的键，如 Linux 下没有对应 KEY_* 码的 sc:0x..
+    UnsendableKey { key: String },
+    // TOML 解析器的错误信息自带出错片段
+    Toml(String),
+}
+
+impl ConfigError {
+    pub fn unknown_key(span: Option<Span>, name: &str) -> Self {
+        ConfigError::UnknownKey {
+            span,
+            name: name.to_string(),
+            suggestion: suggest_key(name),
+        }
+    }
+    
+    pub fn span(&self) -> Option<&Span> {
+        match self {
+            ConfigError::Syntax { span, .. }
+            | ConfigError::UnknownSetting { span, .. }
+            | ConfigError::InvalidValue { span, .. }
+            | ConfigError::OrphanSetting { span, .. }
+            | ConfigError::IncompleteRemap { span, .. }
+            | ConfigError::DuplicateLayer { span, .. } => Some(span),
+            ConfigError::UnknownKey { span, .. } | ConfigError::DuplicateRemapKey { span, .. } => span.as_ref(),
+            ConfigError::Io { .. }
+            | ConfigError::UndefinedLayer { .. }
+            | ConfigError::DuplicateLayerMapping { .. }
+            | ConfigError::RemapCycle { .. }
+            | ConfigError::ModifierOverlap { .. }
+            | ConfigError::UnmatchableKey { .. }
+            | ConfigError::UnsendableKey { .. }
+            | ConfigError::Toml(_) => None,
+        }
+    }
+    
+    // 不含位置的一行说明
+    pub fn message(&self) -> String {
+        match self {
+            ConfigError::Io { path, message } => format!("cannot open configuration file '{}': {}", path, message),
+            ConfigError::Syntax { message, .. } | ConfigError::InvalidValue { message, .. } => message.clone(),
+            ConfigError::UnknownKey { name, suggestion: Some(suggestion), .. } => {
+                format!("invalid key name '{}', did you mean {}?", name, suggestion)
+            }
+            ConfigError::UnknownKey { name, suggestion: None, .. } => format!(
+                "invalid key name '{}' (use a name from the key list, or vk:0x.. / sc:0x.. for raw key codes)",
+                name
+            ),
+            ConfigError::UnknownSetting { name, suggestion: Some(suggestion), .. } => {
+                format!("unknown setting '{}', did you mean {}?", name, suggestion)
+            }
+            ConfigError::UnknownSetting { name, suggestion: None, .. } => format!("unknown setting '{}'", name),
+            ConfigError::OrphanSetting { setting, parent, .. } => format!("{} must come after {}", setting, parent),
+            ConfigError::IncompleteRemap { key, missing, .. } => {
+                format!("incomplete remap of {}: missing {}", key, missing.join(" and "))
+            }
+            ConfigError::DuplicateRemapKey { key, first_line: Some(line), .. } => {
+                format!("{} is remapped twice (first on line {})", key, line)
+            }
+            ConfigError::DuplicateRemapKey { key, first_line: None, .. } => format!("{} is remapped twice", key),
+            ConfigError::DuplicateLayer { name, .. } => format!("layer '{}' is defined twice", name),
+            ConfigError::DuplicateLayerMapping { layer, key } => format!("{} is mapped twice in layer '{}'", key, layer),
+            ConfigError::UndefinedLayer { key, layer } => format!("remap of {} uses undefined layer '{}'", key, layer),
+            ConfigError::RemapCycle { first, second } => {
+                format!(
+                    "{} sends {} as with_other, but {} is remapped to send {}; keys sent by dual-key-remap are not remapped again",
+                    first, second, second, first,
+                )
+            }
+            ConfigError::ModifierOverlap { generic, sided } => {
+                format!("both {} and {} are remapped; remap either the generic or the left/right modifier", generic, sided)
+            }
+            ConfigError::UnmatchableKey { key, match_by: MatchBy::ScanCode } => {
+                format!("{} has no scan code, so it cannot be matched with match_by=scan_code", key)
+            }
+            ConfigError::UnmatchableKey { key, match_by: MatchBy::VirtualKey } => {
+                format!("{} has no virtual key code, so it cannot be matched with match_by=virtual_key", key)
+            }
+            ConfigError::UnsendableKey { key } => format!("{} cannot be sent on this platform", key),
+            ConfigError::Toml(message) => message.clone(),
+        }
+    }
+}
+
+impl ConfigError {
+    // 以警告的形式显示，用于被忽略的未知设置
+    pub fn warning(&self) -> String {
+        Rendered { error: self, heading: "Config warning" }.to_string()
+    }
+}
+
+// 有位置时显示出错的行并用 ^ 标出出错的部分：
+//   Config error (line 2, column 11): invalid key name 'CAPSLOK', did you mean CAPSLOCK?
+//     |
+//   2 | remap_key=CAPSLOK
+//     |           ^^^^^^^
+struct Rendered<'a> {
+    error: &'a ConfigError,
+    heading: &'a str,
+}
+
+impl fmt::Display for Rendered<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if let ConfigError::Io { .. } = self.error {
+            return write!(f, "{}", capitalize(&self.error.message()));
+        }
+        
+        let Some(span) = self.error.span() else {
+            return write!(f, "{}: {}", self.heading, self.error.message());
+        };
+        
+        let gutter = " ".repeat(span.line.to_string().len());
+        writeln!(f, "{} (line {}, column {}): {}", self.heading, span.line, span.column, self.error.message())?;
+        writeln!(f, "{} |", gutter)?;
+        writeln!(f, "{} | {}", span.line, span.text)?;
+        write!(f, "{} | {}{}", gutter, " ".repeat(span.column - 1), "^".repeat(span.len))
+    }
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", Rendered { error: self, heading: "Config error" })
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+fn capitalize(message: &str) -> String {
+    let mut chars = message.chars();
+    match chars.next() {
+        Some(first) => first.to_uppercase().chain(chars).collect(),
+        None => String::new(),
+    }
+}
+
+// 编辑距离（Levenshtein），相邻字符交换也只算一次编辑
+pub fn edit_distance(a: &str, b: &str) -> usize {
+    let a: Vec<char> = a.chars().collect();
+    let b: Vec<char> = b.chars().collect();
+    let mut d = vec![vec![0; b.len() + 1]; a.len() + 1];
+    
+    for (i, row) in d.iter_mut().enumerate() {
+        row[0] = i;
+    }
+    d[0] = (0..=b.len()).collect();
+    
+    for i in 1..=a.len() {
+        for j in 1..=b.len() {
+            let cost = usize::from(a[i - 1] != b[j - 1]);
+            d[i][j] = (d[i - 1][j] + 1).min(d[i][j - 1] + 1).min(d[i - 1][j - 1] + cost);
+            if i > 1 && j > 1 && a[i - 1] == b[j - 2] && a[i - 2] == b[j - 1] {
+                d[i][j] = d[i][j].min(d[i - 2][j - 2] + 1);
+            }
+        }
+    }
+    d[a.len()][b.len()]
+}
+
+// 在候选中找编辑距离最小且足够接近的一个
+pub fn suggest<'a>(name: &str, candidates: impl IntoIterator<Item = &'a str>) -> Option<&'a str> {
+    let max_distance = (name.chars().count() / 3).max(1);
+    
+    candidates.into_iter()
+        .map(|candidate| (edit_distance(name, candidate), candidate))
+        .filter(|(distance, _)| *distance <= max_distance)
+        .min_by_key(|(distance, _)| *distance)
+        .map(|(_, candidate)| candidate)
+}
+
+// 键名不区分大小写，别名也参与匹配
+pub fn suggest_key(name: &str) -> Option<&'static str> {
+    let names = KEYS.iter()
+        .map(|key_def| key_def.name)
+        .chain(KEY_ALIASES.iter().map(|(alias, _)| *alias));
+    suggest(&name.to_uppercase(), names)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::parse_config;
+    
+    #[test]
+    fn edit_distances() {
+        assert_eq!(edit_distance("CAPSLOCK", "CAPSLOCK"), 0);
+        assert_eq!(edit_distance("CAPSLOK", "CAPSLOCK"), 1);
+        assert_eq!(edit_distance("when_alon", "when_alone"), 1);
+        assert_eq!(edit_distance("kitten", "sitting"), 3);
+        assert_eq!(edit_distance("LCRTL", "LCTRL"), 1);
+        assert_eq!(edit_distance("", "abc"), 3);
+    }
+    
+    #[test]
+    fn key_suggestions() {
+        assert_eq!(suggest_key("CAPSLOK"), Some("CAPSLOCK"));
+        assert_eq!(suggest_key("escpe"), Some("ESCAPE"));
+        assert_eq!(suggest_key("LCRTL"), Some("LCTRL"));
+        assert_eq!(suggest_key("NOTHING_LIKE_A_KEY"), None);
+    }
+    
+    #[test]
+    fn renders_snippet_with_caret() {
+        let err = parse_config("# caps\nremap_key=CAPSLOK\n").unwrap_err();
+        assert_eq!(err.to_string(), "\
+Config error (line 2, column 11): invalid key name 'CAPSLOK', did you mean CAPSLOCK?
+  |
+2 | remap_key=CAPSLOK
+  |           ^^^^^^^");
+        
+        let err = parse_config("remap_key=CAPSLOCK\nwhen_alone=ESCAPE, CTRL+Zz\n").unwrap_err();
+        match err {
+            ConfigError::UnknownKey { span: Some(ref span), ref name, .. } => {
+                assert_eq!(name, "Zz");
+                assert_eq!((span.line, span.column, span.len), (2, 25, 2));
+            }
+            ref other => panic!("unexpected error {:?}", other),
+        }
+        
+        // 缩进、等号两侧的空白和多字节字符：列号和长度按字符计
+        let err = parse_config("  remap_key = ÄCAPS  \n").unwrap_err();
+        match err {
+            ConfigError::UnknownKey { span: Some(ref span), .. } => assert_eq!((span.column, span.len), (15, 5)),
+            ref other => panic!("unexpected error {:?}", other),
+        }
+    }
+    
+    #[test]
+    fn error_kinds() {
+        let err = parse_config("when_alone=ESCAPE\n").unwrap_err();
+        assert!(matches!(err, ConfigError::OrphanSetting { setting: "when_alone", parent: "remap_key", .. }), "{:?}", err);
+        
+        let err = parse_config("remap_key=CAPSLOCK\nwhen_alone=ESCAPE\nremap_key=TAB\n").unwrap_err();
+        match err {
+            ConfigError::IncompleteRemap { ref span, ref key, ref missing } => {
+                assert_eq!((span.line, key.as_str(), missing.as_slice()), (1, "CAPSLOCK", ["with_other"].as_slice()));
+            }
+            ref other => panic!("unexpected error {:?}", other),
+        }
+        
+        let err = parse_config("remap_key=CAPSLOCK\nwhen_alone=ESCAPE\nwith_other=CTRL\nremap_key=CAPSLOCK\n").unwrap_err();
+        assert!(matches!(err, ConfigError::DuplicateRemapKey { first_line: Some(1), .. }), "{:?}", err);
+        
+        let err = parse_config("remap_key=CAPSLOCK\nwhen_alon=ESCAPE\n").unwrap_err();
+        assert!(matches!(err, ConfigError::UnknownSetting { suggestion: Some("when_alone"), .. }), "{:?}", err);
+        
+        let err = parse_config("remap_key=CAPSLOCK\nwhen_alone=ESCAPE\nwith_other=CTRL\nhold_timeout_ms=soon\n").unwrap_err();
+        assert!(matches!(err, ConfigError::InvalidValue { ref span, .. } if span.column == 17), "{:?}", err);
+        
+        let err = crate::config::load_config("/nonexistent/config.txt").unwrap_err();
+        assert!(matches!(err, ConfigError::Io { .. }), "{:?}", err);
+        assert!(err.to_string().starts_with("Cannot open configuration file"), "{}", err);
+    }
+}
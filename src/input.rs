@@ -1,4 +1,4 @@
-use crate::keys::KeyDef;
+use crate::keys::{find_key_for_char, find_key_by_name, key_for_codes, KeyDef};
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum Direction {
@@ -6,12 +6,57 @@ pub enum Direction {
     Down,
 }
 
-pub fn send_input(key_def: &KeyDef, direction: Direction) -> Result<(), String> {
-    #[cfg(target_os = "windows")]
-    {
+// 输入事件的来源：物理按键、本程序注入的按键，或其他软件（如 AutoHotkey、屏幕键盘）注入的按键
+// Linux 下接管整个设备，只有物理按键
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(not(target_os = "windows"), allow(dead_code))]
+pub enum InputSource {
+    Physical,
+    Own,
+    Foreign,
+}
+
+// 输出后端：RemapManager 通过它发送重映射后的按键
+pub trait OutputSink {
+    fn send_key(&mut self, key_def: &KeyDef, direction: Direction) -> Result<(), String>;
+    
+    // 重新注入双功能键未决定期间被拦截的原始按键
+    fn replay_key(&mut self, virt_code: u32, scan_code: u32, direction: Direction) -> Result<(), String> {
+        self.send_key(&key_for_codes(virt_code, scan_code), direction)
+    }
+    
+    // 默认按美式布局逐个按键输入文本，能直接输入 Unicode 的后端可以覆盖
+    fn send_text(&mut self, text: &str) -> Result<(), String> {
+        let shift = find_key_by_name("LSHIFT").ok_or("Missing LSHIFT key")?;
+        
+        for c in text.chars() {
+            let (key_def, shifted) = find_key_for_char(c)
+                .ok_or_else(|| format!("Cannot type character {:?}", c))?;
+            
+            if shifted {
+                self.send_key(&shift, Direction::Down)?;
+            }
+            self.send_key(&key_def, Direction::Down)?;
+            self.send_key(&key_def, Direction::Up)?;
+            if shifted {
+                self.send_key(&shift, Direction::Up)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+// Windows 下通过 SendInput 注入按键
+#[cfg(target_os = "windows")]
+#[derive(Debug, Default)]
+pub struct SendInputSink;
+
+#[cfg(target_os = "windows")]
+impl OutputSink for SendInputSink {
+    fn send_key(&mut self, key_def: &KeyDef, direction: Direction) -> Result<(), String> {
         use windows::Win32::UI::Input::KeyboardAndMouse::*;
         
-        let mut input = INPUT {
+        let input = INPUT {
             r#type: INPUT_KEYBOARD,
             Anonymous: INPUT_0 {
                 ki: KEYBDINPUT {
@@ -28,14 +73,19 @@ pub fn send_input(key_def: &KeyDef, direction: Direction) -> Result<(), String>
                             flags |= KEYEVENTF_EXTENDEDKEY;
                         }
                         
+                        // 只有扫描码的原始键码按扫描码发送
+                        if key_def.virt_code == 0 {
+                            flags |= KEYEVENTF_SCANCODE;
+                        }
+                        
                         flags
                     },
                     time: 0,
-                    dwExtraInfo: 0xFFC3CED7, // 标识这是我们注入的按键
+                    dwExtraInfo: INJECTED_KEY_ID, // 标识这是我们注入的按键
                 },
             },
         };
-
+        
         unsafe {
             let result = SendInput(&[input], std::mem::size_of::<INPUT>() as i32);
             if result == 0 {
@@ -46,9 +96,47 @@ pub fn send_input(key_def: &KeyDef, direction: Direction) -> Result<(), String>
         }
     }
     
-    #[cfg(not(target_os = "windows"))]
-    {
-        // 在非Windows系统上，只是打印模拟信息
+    // 以 Unicode 字符直接输入，不依赖键盘布局
+    fn send_text(&mut self, text: &str) -> Result<(), String> {
+        use windows::Win32::UI::Input::KeyboardAndMouse::*;
+        
+        let mut inputs = Vec::new();
+        for unit in text.encode_utf16() {
+            for flags in [KEYEVENTF_UNICODE, KEYEVENTF_UNICODE | KEYEVENTF_KEYUP] {
+                inputs.push(INPUT {
+                    r#type: INPUT_KEYBOARD,
+                    Anonymous: INPUT_0 {
+                        ki: KEYBDINPUT {
+                            wVk: VIRTUAL_KEY(0),
+                            wScan: unit,
+                            dwFlags: flags,
+                            time: 0,
+                            dwExtraInfo: INJECTED_KEY_ID, // 标识这是我们注入的按键
+                        },
+                    },
+                });
+            }
+        }
+        
+        unsafe {
+            let result = SendInput(&inputs, std::mem::size_of::<INPUT>() as i32);
+            if result as usize != inputs.len() {
+                Err("Failed to send text input".to_string())
+            } else {
+                Ok(())
+            }
+        }
+    }
+}
+
+// 在其他系统上，只是打印模拟信息
+#[cfg(not(any(target_os = "windows", target_os = "linux")))]
+#[derive(Debug, Default)]
+pub struct StdoutSink;
+
+#[cfg(not(any(target_os = "windows", target_os = "linux")))]
+impl OutputSink for StdoutSink {
+    fn send_key(&mut self, key_def: &KeyDef, direction: Direction) -> Result<(), String> {
         let direction_str = match direction {
             Direction::Up => "UP",
             Direction::Down => "DOWN",
@@ -58,5 +146,83 @@ pub fn send_input(key_def: &KeyDef, direction: Direction) -> Result<(), String>
     }
 }
 
+#[cfg(test)]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RecordedEvent {
+    Key(KeyDef, Direction),
+    Text(String),
+}
+
+// 记录所有输出事件，供测试断言
+#[cfg(test)]
+#[derive(Debug, Default)]
+pub struct RecordingSink {
+    pub events: Vec<RecordedEvent>,
+}
+
+#[cfg(test)]
+impl OutputSink for RecordingSink {
+    fn send_key(&mut self, key_def: &KeyDef, direction: Direction) -> Result<(), String> {
+        self.events.push(RecordedEvent::Key(*key_def, direction));
+        Ok(())
+    }
+    
+    fn send_text(&mut self, text: &str) -> Result<(), String> {
+        self.events.push(RecordedEvent::Text(text.to_string()));
+        Ok(())
+    }
+}
+
+// 输出后端能否发送这个键，在加载配置时检查。Linux 只能发送有对应 KEY_* 码的扫描码
+#[cfg(target_os = "linux")]
+pub fn can_send(key_def: &KeyDef) -> bool {
+    crate::linux::scan_code_to_key_code(key_def.scan_code).is_some()
+}
+
+// SendInput 可以按虚拟键码或扫描码发送，原始键码至少有其中一种
+#[cfg(not(target_os = "linux"))]
+pub fn can_send(_key_def: &KeyDef) -> bool {
+    true
+}
+
+// SendInputSink 在 dwExtraInfo 中写入的标记，用于识别自己注入的按键
+#[cfg(target_os = "windows")]
+pub const INJECTED_KEY_ID: usize = 0xFFC3CED7;
+
 // 鼠标虚拟键码，用于处理鼠标输入
 pub const MOUSE_DUMMY_VK: u32 = 0xFF;
+
+// 程序启动以来的毫秒数，作为 RemapManager 的时间源
+#[cfg(any(target_os = "windows", target_os = "linux"))]
+pub fn timestamp_ms() -> u64 {
+    use std::sync::OnceLock;
+    use std::time::Instant;
+    
+    static START: OnceLock<Instant> = OnceLock::new();
+    START.get_or_init(Instant::now).elapsed().as_millis() as u64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    
+    // 只实现 send_key，使用默认的 send_text
+    #[derive(Default)]
+    struct KeysOnly(Vec<String>);
+    
+    impl OutputSink for KeysOnly {
+        fn send_key(&mut self, key_def: &KeyDef, direction: Direction) -> Result<(), String> {
+            self.0.push(format!("{} {:?}", key_def.name, direction));
+            Ok(())
+        }
+    }
+    
+    #[test]
+    fn default_send_text_types_keys() {
+        let mut sink = KeysOnly::default();
+        sink.send_text("a:").unwrap();
+        assert_eq!(sink.0, ["A Down", "A Up", "LSHIFT Down", "SEMICOLON Down", "SEMICOLON Up", "LSHIFT Up"]);
+        
+        assert!(sink.send_text("é").is_err());
+    }
+}
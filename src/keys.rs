@@ -1,3 +1,5 @@
+use std::sync::Mutex;
+
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub struct KeyDef {
     pub name: &'static str,
@@ -31,6 +33,16 @@ pub const KEYS: &[KeyDef] = &[
     KeyDef { name: "DOWN", virt_code: 0x28, scan_code: 0xE050 },
     KeyDef { name: "LEFT", virt_code: 0x25, scan_code: 0xE04B },
     KeyDef { name: "RIGHT", virt_code: 0x27, scan_code: 0xE04D },
+    KeyDef { name: "INSERT", virt_code: 0x2D, scan_code: 0xE052 },
+    KeyDef { name: "PRINTSCREEN", virt_code: 0x2C, scan_code: 0xE037 },
+    KeyDef { name: "SCROLLLOCK", virt_code: 0x91, scan_code: 0x46 },
+    KeyDef { name: "PAUSE", virt_code: 0x13, scan_code: 0x45 },
+    KeyDef { name: "NUMLOCK", virt_code: 0x90, scan_code: 0xE045 },
+    KeyDef { name: "LWIN", virt_code: 0x5B, scan_code: 0xE05B },
+    KeyDef { name: "RWIN", virt_code: 0x5C, scan_code: 0xE05C },
+    KeyDef { name: "APPS", virt_code: 0x5D, scan_code: 0xE05D },
+    KeyDef { name: "SLEEP", virt_code: 0x5F, scan_code: 0xE05F },
+    KeyDef { name: "HELP", virt_code: 0x2F, scan_code: 0xE03B },
     // 字母键
     KeyDef { name: "A", virt_code: 0x41, scan_code: 0x1E },
     KeyDef { name: "B", virt_code: 0x42, scan_code: 0x30 },
@@ -82,8 +94,358 @@ pub const KEYS: &[KeyDef] = &[
     KeyDef { name: "F10", virt_code: 0x79, scan_code: 0x44 },
     KeyDef { name: "F11", virt_code: 0x7A, scan_code: 0x57 },
     KeyDef { name: "F12", virt_code: 0x7B, scan_code: 0x58 },
+    KeyDef { name: "F13", virt_code: 0x7C, scan_code: 0x64 },
+    KeyDef { name: "F14", virt_code: 0x7D, scan_code: 0x65 },
+    KeyDef { name: "F15", virt_code: 0x7E, scan_code: 0x66 },
+    KeyDef { name: "F16", virt_code: 0x7F, scan_code: 0x67 },
+    KeyDef { name: "F17", virt_code: 0x80, scan_code: 0x68 },
+    KeyDef { name: "F18", virt_code: 0x81, scan_code: 0x69 },
+    KeyDef { name: "F19", virt_code: 0x82, scan_code: 0x6A },
+    KeyDef { name: "F20", virt_code: 0x83, scan_code: 0x6B },
+    KeyDef { name: "F21", virt_code: 0x84, scan_code: 0x6C },
+    KeyDef { name: "F22", virt_code: 0x85, scan_code: 0x6D },
+    KeyDef { name: "F23", virt_code: 0x86, scan_code: 0x6E },
+    KeyDef { name: "F24", virt_code: 0x87, scan_code: 0x76 },
+    // 数字小键盘
+    KeyDef { name: "NUMPAD0", virt_code: 0x60, scan_code: 0x52 },
+    KeyDef { name: "NUMPAD1", virt_code: 0x61, scan_code: 0x4F },
+    KeyDef { name: "NUMPAD2", virt_code: 0x62, scan_code: 0x50 },
+    KeyDef { name: "NUMPAD3", virt_code: 0x63, scan_code: 0x51 },
+    KeyDef { name: "NUMPAD4", virt_code: 0x64, scan_code: 0x4B },
+    KeyDef { name: "NUMPAD5", virt_code: 0x65, scan_code: 0x4C },
+    KeyDef { name: "NUMPAD6", virt_code: 0x66, scan_code: 0x4D },
+    KeyDef { name: "NUMPAD7", virt_code: 0x67, scan_code: 0x47 },
+    KeyDef { name: "NUMPAD8", virt_code: 0x68, scan_code: 0x48 },
+    KeyDef { name: "NUMPAD9", virt_code: 0x69, scan_code: 0x49 },
+    KeyDef { name: "MULTIPLY", virt_code: 0x6A, scan_code: 0x37 },
+    KeyDef { name: "ADD", virt_code: 0x6B, scan_code: 0x4E },
+    KeyDef { name: "SUBTRACT", virt_code: 0x6D, scan_code: 0x4A },
+    KeyDef { name: "DECIMAL", virt_code: 0x6E, scan_code: 0x53 },
+    KeyDef { name: "DIVIDE", virt_code: 0x6F, scan_code: 0xE035 },
+    KeyDef { name: "NUMPAD_ENTER", virt_code: 0x0D, scan_code: 0xE01C },
+    KeyDef { name: "SEPARATOR", virt_code: 0x6C, scan_code: 0x7E },
+    // NumLock 关闭时小键盘 5 发送的键
+    KeyDef { name: "CLEAR", virt_code: 0x0C, scan_code: 0x4C },
+    // 符号键（美式布局）
+    KeyDef { name: "SEMICOLON", virt_code: 0xBA, scan_code: 0x27 },
+    KeyDef { name: "EQUALS", virt_code: 0xBB, scan_code: 0x0D },
+    KeyDef { name: "COMMA", virt_code: 0xBC, scan_code: 0x33 },
+    KeyDef { name: "MINUS", virt_code: 0xBD, scan_code: 0x0C },
+    KeyDef { name: "PERIOD", virt_code: 0xBE, scan_code: 0x34 },
+    KeyDef { name: "SLASH", virt_code: 0xBF, scan_code: 0x35 },
+    KeyDef { name: "GRAVE", virt_code: 0xC0, scan_code: 0x29 },
+    KeyDef { name: "LBRACKET", virt_code: 0xDB, scan_code: 0x1A },
+    KeyDef { name: "BACKSLASH", virt_code: 0xDC, scan_code: 0x2B },
+    KeyDef { name: "RBRACKET", virt_code: 0xDD, scan_code: 0x1B },
+    KeyDef { name: "QUOTE", virt_code: 0xDE, scan_code: 0x28 },
+    KeyDef { name: "OEM_102", virt_code: 0xE2, scan_code: 0x56 },
+    // 随布局变化，英式布局下是 ` 键
+    KeyDef { name: "OEM_8", virt_code: 0xDF, scan_code: 0x29 },
+    // 输入法键
+    KeyDef { name: "KANA", virt_code: 0x15, scan_code: 0x70 },
+    KeyDef { name: "CONVERT", virt_code: 0x1C, scan_code: 0x79 },
+    KeyDef { name: "NONCONVERT", virt_code: 0x1D, scan_code: 0x7B },
+    KeyDef { name: "HANJA", virt_code: 0x19, scan_code: 0xF1 },
+    // 以下没有标准的扫描码，只能按虚拟键码匹配和发送
+    KeyDef { name: "JUNJA", virt_code: 0x17, scan_code: 0 },
+    KeyDef { name: "FINAL", virt_code: 0x18, scan_code: 0 },
+    KeyDef { name: "ACCEPT", virt_code: 0x1E, scan_code: 0 },
+    KeyDef { name: "MODECHANGE", virt_code: 0x1F, scan_code: 0 },
+    KeyDef { name: "IME_ON", virt_code: 0x16, scan_code: 0 },
+    KeyDef { name: "IME_OFF", virt_code: 0x1A, scan_code: 0 },
+    KeyDef { name: "PROCESSKEY", virt_code: 0xE5, scan_code: 0 },
+    // 媒体键
+    KeyDef { name: "VOLUME_MUTE", virt_code: 0xAD, scan_code: 0xE020 },
+    KeyDef { name: "VOLUME_DOWN", virt_code: 0xAE, scan_code: 0xE02E },
+    KeyDef { name: "VOLUME_UP", virt_code: 0xAF, scan_code: 0xE030 },
+    KeyDef { name: "MEDIA_NEXT_TRACK", virt_code: 0xB0, scan_code: 0xE019 },
+    KeyDef { name: "MEDIA_PREV_TRACK", virt_code: 0xB1, scan_code: 0xE010 },
+    KeyDef { name: "MEDIA_STOP", virt_code: 0xB2, scan_code: 0xE024 },
+    KeyDef { name: "MEDIA_PLAY_PAUSE", virt_code: 0xB3, scan_code: 0xE022 },
+    KeyDef { name: "LAUNCH_MAIL", virt_code: 0xB4, scan_code: 0xE06C },
+    KeyDef { name: "LAUNCH_MEDIA_SELECT", virt_code: 0xB5, scan_code: 0xE06D },
+    KeyDef { name: "LAUNCH_APP1", virt_code: 0xB6, scan_code: 0xE06B },
+    KeyDef { name: "LAUNCH_APP2", virt_code: 0xB7, scan_code: 0xE021 },
+    KeyDef { name: "PLAY", virt_code: 0xFA, scan_code: 0 },
+    KeyDef { name: "ZOOM", virt_code: 0xFB, scan_code: 0 },
+    // 浏览器键
+    KeyDef { name: "BROWSER_BACK", virt_code: 0xA6, scan_code: 0xE06A },
+    KeyDef { name: "BROWSER_FORWARD", virt_code: 0xA7, scan_code: 0xE069 },
+    KeyDef { name: "BROWSER_REFRESH", virt_code: 0xA8, scan_code: 0xE067 },
+    KeyDef { name: "BROWSER_STOP", virt_code: 0xA9, scan_code: 0xE068 },
+    KeyDef { name: "BROWSER_SEARCH", virt_code: 0xAA, scan_code: 0xE065 },
+    KeyDef { name: "BROWSER_FAVORITES", virt_code: 0xAB, scan_code: 0xE066 },
+    KeyDef { name: "BROWSER_HOME", virt_code: 0xAC, scan_code: 0xE032 },
+    // 旧式终端和特殊用途的键，没有标准的扫描码
+    KeyDef { name: "CANCEL", virt_code: 0x03, scan_code: 0 },
+    KeyDef { name: "SELECT", virt_code: 0x29, scan_code: 0 },
+    KeyDef { name: "PRINT", virt_code: 0x2A, scan_code: 0 },
+    KeyDef { name: "EXECUTE", virt_code: 0x2B, scan_code: 0 },
+    KeyDef { name: "PACKET", virt_code: 0xE7, scan_code: 0 },
+    KeyDef { name: "ATTN", virt_code: 0xF6, scan_code: 0 },
+    KeyDef { name: "CRSEL", virt_code: 0xF7, scan_code: 0 },
+    KeyDef { name: "EXSEL", virt_code: 0xF8, scan_code: 0 },
+    KeyDef { name: "EREOF", virt_code: 0xF9, scan_code: 0 },
+    KeyDef { name: "NONAME", virt_code: 0xFC, scan_code: 0 },
+    KeyDef { name: "PA1", virt_code: 0xFD, scan_code: 0 },
+    KeyDef { name: "OEM_CLEAR", virt_code: 0xFE, scan_code: 0 },
+];
+
+// 键名别名 -> KEYS 中的标准名称
+pub const KEY_ALIASES: &[(&str, &str)] = &[
+    ("ESC", "ESCAPE"),
+    ("CAPS", "CAPSLOCK"),
+    ("RETURN", "ENTER"),
+    ("DEL", "DELETE"),
+    ("INS", "INSERT"),
+    ("PGUP", "PAGEUP"),
+    ("PGDN", "PAGEDOWN"),
+    ("PRTSC", "PRINTSCREEN"),
+    ("CONTROL", "CTRL"),
+    ("LCONTROL", "LCTRL"),
+    ("RCONTROL", "RCTRL"),
+    ("WIN", "LWIN"),
+    ("SUPER", "LWIN"),
+    ("LSUPER", "LWIN"),
+    ("RSUPER", "RWIN"),
+    ("CMD", "LWIN"),
+    ("LCMD", "LWIN"),
+    ("RCMD", "RWIN"),
+    ("MENU", "APPS"),
+    ("OEM_1", "SEMICOLON"),
+    ("OEM_PLUS", "EQUALS"),
+    ("OEM_COMMA", "COMMA"),
+    ("OEM_MINUS", "MINUS"),
+    ("OEM_PERIOD", "PERIOD"),
+    ("OEM_2", "SLASH"),
+    ("OEM_3", "GRAVE"),
+    ("BACKTICK", "GRAVE"),
+    ("OEM_4", "LBRACKET"),
+    ("OEM_5", "BACKSLASH"),
+    ("OEM_6", "RBRACKET"),
+    ("OEM_7", "QUOTE"),
+    ("APOSTROPHE", "QUOTE"),
+    ("KANJI", "HANJA"),
+    ("HANGUL", "KANA"),
 ];
 
 pub fn find_key_by_name(name: &str) -> Option<KeyDef> {
+    let name = KEY_ALIASES.iter()
+        .find(|(alias, _)| alias.eq_ignore_ascii_case(name))
+        .map_or(name, |(_, canonical)| *canonical);
+    
     KEYS.iter().find(|key| key.name.eq_ignore_ascii_case(name)).copied()
 }
+
+// 按扫描码查找；通用的 CTRL/SHIFT/ALT 与左侧键扫描码相同，优先返回区分左右的键。
+// 没有扫描码的键不参与查找
+pub fn find_key_by_scan_code(scan_code: u32) -> Option<KeyDef> {
+    KEYS.iter()
+        .filter(|key| !(0x10..=0x12).contains(&key.virt_code) && key.scan_code != 0)
+        .find(|key| key.scan_code == scan_code)
+        .copied()
+}
+
+// 区分左右的修饰键对应的通用修饰键：LSHIFT/RSHIFT -> SHIFT，LCTRL/RCTRL -> CTRL，LALT/RALT -> ALT
+pub fn generic_modifier(virt_code: u32) -> Option<u32> {
+    match virt_code {
+        0xA0 | 0xA1 => Some(0x10),
+        0xA2 | 0xA3 => Some(0x11),
+        0xA4 | 0xA5 => Some(0x12),
+        _ => None,
+    }
+}
+
+// 通用修饰键对应的左右两个键
+pub fn sided_modifiers(virt_code: u32) -> Option<[u32; 2]> {
+    match virt_code {
+        0x10 => Some([0xA0, 0xA1]),
+        0x11 => Some([0xA2, 0xA3]),
+        0x12 => Some([0xA4, 0xA5]),
+        _ => None,
+    }
+}
+
+// 配置中的键作为输入时实际匹配哪些键，供 check 命令说明
+pub fn describe_input_match(key_def: &KeyDef) -> Option<String> {
+    let name_of = |virt_code: u32| {
+        KEYS.iter().find(|key| key.virt_code == virt_code).map_or("?", |key| key.name)
+    };
+    
+    if let Some([left, right]) = sided_modifiers(key_def.virt_code) {
+        return Some(format!("{} matches both {} and {}", key_def.name, name_of(left), name_of(right)));
+    }
+    generic_modifier(key_def.virt_code).map(|generic| {
+        format!("{} matches only this side, use {} to match both sides", key_def.name, name_of(generic))
+    })
+}
+
+// 美式布局下的符号：(字符, 键名, 是否需要 SHIFT)
+const CHAR_KEYS: &[(char, &str, bool)] = &[
+    (' ', "SPACE", false), ('\n', "ENTER", false), ('\t', "TAB", false),
+    (';', "SEMICOLON", false), (':', "SEMICOLON", true),
+    ('=', "EQUALS", false), ('+', "EQUALS", true),
+    (',', "COMMA", false), ('<', "COMMA", true),
+    ('-', "MINUS", false), ('_', "MINUS", true),
+    ('.', "PERIOD", false), ('>', "PERIOD", true),
+    ('/', "SLASH", false), ('?', "SLASH", true),
+    ('`', "GRAVE", false), ('~', "GRAVE", true),
+    ('[', "LBRACKET", false), ('{', "LBRACKET", true),
+    ('\\', "BACKSLASH", false), ('|', "BACKSLASH", true),
+    (']', "RBRACKET", false), ('}', "RBRACKET", true),
+    ('\'', "QUOTE", false), ('"', "QUOTE", true),
+    ('!', "1", true), ('@', "2", true), ('#', "3", true), ('$', "4", true), ('%', "5", true),
+    ('^', "6", true), ('&', "7", true), ('*', "8", true), ('(', "9", true), (')', "0", true),
+];
+
+// 按美式布局输入字符所需的键，以及是否需要按住 SHIFT
+pub fn find_key_for_char(c: char) -> Option<(KeyDef, bool)> {
+    if c.is_ascii_alphanumeric() {
+        let name = c.to_ascii_uppercase().to_string();
+        return find_key_by_name(&name).map(|key| (key, c.is_ascii_uppercase()));
+    }
+    
+    let &(_, name, shift) = CHAR_KEYS.iter().find(|(ch, _, _)| *ch == c)?;
+    find_key_by_name(name).map(|key| (key, shift))
+}
+
+// 键名，或 "vk:0xE2"、"sc:0xE05B"、"vk:0xE2 sc:0x56" 形式的原始键码
+pub fn parse_key(spec: &str) -> Option<KeyDef> {
+    find_key_by_name(spec).or_else(|| parse_raw_key(spec))
+}
+
+fn parse_raw_key(spec: &str) -> Option<KeyDef> {
+    let mut virt_code = None;
+    let mut scan_code = None;
+    
+    for part in spec.split_whitespace() {
+        let (kind, value) = part.split_once(':')?;
+        let value = value.strip_prefix("0x").or_else(|| value.strip_prefix("0X")).unwrap_or(value);
+        let value = u32::from_str_radix(value, 16).ok()?;
+        
+        match kind.to_ascii_lowercase().as_str() {
+            "vk" if virt_code.is_none() && (0x01..=0xFE).contains(&value) => virt_code = Some(value),
+            "sc" if scan_code.is_none() && value != 0 && value <= 0xE1FF => scan_code = Some(value),
+            _ => return None,
+        }
+    }
+    
+    // 只给出一种键码时，尽量从键表补全另一种
+    let (virt_code, scan_code) = match (virt_code, scan_code) {
+        (Some(vk), Some(sc)) => (vk, sc),
+        (Some(vk), None) => match KEYS.iter().find(|key| key.virt_code == vk) {
+            Some(key) => return Some(*key),
+            None => (vk, 0),
+        },
+        (None, Some(sc)) => match find_key_by_scan_code(sc) {
+            Some(key) => return Some(key),
+            None => (0, sc),
+        },
+        (None, None) => return None,
+    };
+    
+    Some(key_for_codes(virt_code, scan_code))
+}
+
+// 由键码得到 KeyDef：键表中有完全相同的键时使用它，否则以原始键码命名
+pub fn key_for_codes(virt_code: u32, scan_code: u32) -> KeyDef {
+    if let Some(key) = KEYS.iter().find(|key| key.virt_code == virt_code && key.scan_code == scan_code) {
+        return *key;
+    }
+    
+    let name = match (virt_code, scan_code) {
+        (0, sc) => format!("sc:0x{:02X}", sc),
+        (vk, 0) => format!("vk:0x{:02X}", vk),
+        (vk, sc) => format!("vk:0x{:02X} sc:0x{:02X}", vk, sc),
+    };
+    
+    KeyDef { name: intern_name(name), virt_code, scan_code }
+}
+
+// KeyDef 的名称是 &'static str，原始键码的名称在这里驻留，重复加载配置不会重复分配
+fn intern_name(name: String) -> &'static str {
+    static NAMES: Mutex<Vec<&'static str>> = Mutex::new(Vec::new());
+    
+    let mut names = NAMES.lock().unwrap();
+    if let Some(existing) = names.iter().find(|existing| **existing == name) {
+        return existing;
+    }
+    
+    let name: &'static str = Box::leak(name.into_boxed_str());
+    names.push(name);
+    name
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    
+    #[test]
+    fn names_are_unique() {
+        for (i, key) in KEYS.iter().enumerate() {
+            assert!(KEYS[i + 1..].iter().all(|other| other.name != key.name), "duplicate key {}", key.name);
+            assert!(KEY_ALIASES.iter().all(|(alias, _)| *alias != key.name), "alias shadows key {}", key.name);
+        }
+    }
+    
+    #[test]
+    fn aliases_resolve_to_keys() {
+        for (alias, canonical) in KEY_ALIASES {
+            assert_eq!(find_key_by_name(alias).map(|key| key.name), Some(*canonical), "alias {}", alias);
+        }
+        assert_eq!(find_key_by_name("super"), find_key_by_name("LWIN"));
+        assert_eq!(find_key_by_name("Cmd").unwrap().scan_code, 0xE05B);
+    }
+    
+    #[test]
+    fn generic_and_sided_modifiers() {
+        for name in ["SHIFT", "CTRL", "ALT"] {
+            let generic = find_key_by_name(name).unwrap().virt_code;
+            let sides = sided_modifiers(generic).unwrap();
+            assert!(sides.iter().all(|side| generic_modifier(*side) == Some(generic)), "{}", name);
+        }
+        
+        let ctrl = find_key_by_name("CTRL").unwrap();
+        assert_eq!(describe_input_match(&ctrl).unwrap(), "CTRL matches both LCTRL and RCTRL");
+        let ralt = find_key_by_name("RALT").unwrap();
+        assert_eq!(describe_input_match(&ralt).unwrap(), "RALT matches only this side, use ALT to match both sides");
+        assert_eq!(describe_input_match(&find_key_by_name("CAPSLOCK").unwrap()), None);
+    }
+    
+    #[test]
+    fn raw_key_codes() {
+        let both = parse_key("vk:0xE2 sc:0x56").unwrap();
+        assert_eq!(both, find_key_by_name("OEM_102").unwrap());
+        
+        let vk_only = parse_key("vk:0x14").unwrap();
+        assert_eq!(vk_only, find_key_by_name("CAPSLOCK").unwrap());
+        assert_eq!(parse_key("vk:0x0C").unwrap().name, "CLEAR");
+        assert_eq!(parse_key("vk:0xFA").unwrap().name, "PLAY");
+        assert_eq!(parse_key("vk:0x16").unwrap().name, "IME_ON");
+        assert_eq!(parse_key("vk:0xFE").unwrap().name, "OEM_CLEAR");
+        
+        let sc_only = parse_key("SC:0xE05B").unwrap();
+        assert_eq!(sc_only, find_key_by_name("LWIN").unwrap());
+        
+        let unknown = parse_key("vk:0xE1 sc:0x73").unwrap();
+        assert_eq!((unknown.name, unknown.virt_code, unknown.scan_code), ("vk:0xE1 sc:0x73", 0xE1, 0x73));
+        assert!(std::ptr::eq(unknown.name, parse_key("vk:0xe1 sc:0x73").unwrap().name));
+        
+        let scan_only = parse_key("sc:0x7D").unwrap();
+        assert_eq!((scan_only.name, scan_only.virt_code, scan_only.scan_code), ("sc:0x7D", 0, 0x7D));
+        
+        assert_eq!(parse_key("vk:0x1FF"), None);
+        assert_eq!(parse_key("vk:zz"), None);
+        assert_eq!(parse_key("vk:0x41 vk:0x42"), None);
+        assert_eq!(parse_key("kc:0x41"), None);
+    }
+    
+    #[test]
+    fn chars_map_to_us_layout_keys() {
+        assert_eq!(find_key_for_char('a'), Some((find_key_by_name("A").unwrap(), false)));
+        assert_eq!(find_key_for_char('A'), Some((find_key_by_name("A").unwrap(), true)));
+        assert_eq!(find_key_for_char('7'), Some((find_key_by_name("7").unwrap(), false)));
+        assert_eq!(find_key_for_char('{'), Some((find_key_by_name("LBRACKET").unwrap(), true)));
+        assert_eq!(find_key_for_char('é'), None);
+    }
+}
@@ -0,0 +1,302 @@
+use crate::config::Config;
+use crate::input::{timestamp_ms, Direction, InputSource, OutputSink};
+use crate::keys::{find_key_by_scan_code, KeyDef};
+use crate::remap::RemapManager;
+use crate::watcher::ConfigWatcher;
+use evdev::uinput::{VirtualDevice, VirtualDeviceBuilder};
+use evdev::{AttributeSet, Device, EventType, InputEvent, InputEventKind, Key};
+use std::path::PathBuf;
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::thread;
+use std::time::Duration;
+
+const VIRTUAL_DEVICE_NAME: &str = "dual-key-remap virtual keyboard";
+
+// 与 Linux KEY_* 码数值不同的扫描码（0x59 以后的 set 1 扫描码和 0xE0 前缀的扩展键）
+// 其余扫描码 0x01-0x58 与 KEY_* 码数值相同；没有对应扫描码的 KEY_* 码记为 0x10000 + 键码，
+// 以便缓冲的按键能原样重放
+const RAW_KEY_CODE_FLAG: u32 = 0x10000;
+const SCAN_CODE_EXCEPTIONS: &[(u32, u16)] = &[
+    (0x45, 119),   // KEY_PAUSE
+    (0x59, 117),   // KEY_KPEQUAL
+    (0x5C, 95),    // KEY_KPJPCOMMA
+    (0x64, 183),   // KEY_F13
+    (0x65, 184),   // KEY_F14
+    (0x66, 185),   // KEY_F15
+    (0x67, 186),   // KEY_F16
+    (0x68, 187),   // KEY_F17
+    (0x69, 188),   // KEY_F18
+    (0x6A, 189),   // KEY_F19
+    (0x6B, 190),   // KEY_F20
+    (0x6C, 191),   // KEY_F21
+    (0x6D, 192),   // KEY_F22
+    (0x6E, 193),   // KEY_F23
+    (0x76, 194),   // KEY_F24
+    (0x70, 93),    // KEY_KATAKANAHIRAGANA
+    (0x73, 89),    // KEY_RO
+    (0x77, 91),    // KEY_HIRAGANA
+    (0x78, 90),    // KEY_KATAKANA
+    (0x79, 92),    // KEY_HENKAN
+    (0x7B, 94),    // KEY_MUHENKAN
+    (0x7D, 124),   // KEY_YEN
+    (0x7E, 121),   // KEY_KPCOMMA
+    (0xF1, 123),   // KEY_HANJA
+    (0xF2, 122),   // KEY_HANGEUL
+    // 部分键盘发送的另一组扫描码，键码反查时使用上面的 0xF1、0xF2
+    (0x71, 123),   // KEY_HANJA
+    (0x72, 122),   // KEY_HANGEUL
+    (0xE010, 165), // KEY_PREVIOUSSONG
+    (0xE019, 163), // KEY_NEXTSONG
+    (0xE01C, 96),  // KEY_KPENTER
+    (0xE01D, 97),  // KEY_RIGHTCTRL
+    (0xE020, 113), // KEY_MUTE
+    (0xE021, 140), // KEY_CALC
+    (0xE022, 164), // KEY_PLAYPAUSE
+    (0xE024, 166), // KEY_STOPCD
+    (0xE02E, 114), // KEY_VOLUMEDOWN
+    (0xE030, 115), // KEY_VOLUMEUP
+    (0xE032, 172), // KEY_HOMEPAGE
+    (0xE035, 98),  // KEY_KPSLASH
+    (0xE037, 99),  // KEY_SYSRQ
+    (0xE03B, 138), // KEY_HELP
+    (0xE038, 100), // KEY_RIGHTALT
+    (0xE045, 69),  // KEY_NUMLOCK
+    (0xE047, 102), // KEY_HOME
+    (0xE048, 103), // KEY_UP
+    (0xE049, 104), // KEY_PAGEUP
+    (0xE04B, 105), // KEY_LEFT
+    (0xE04D, 106), // KEY_RIGHT
+    (0xE04F, 107), // KEY_END
+    (0xE050, 108), // KEY_DOWN
+    (0xE051, 109), // KEY_PAGEDOWN
+    (0xE052, 110), // KEY_INSERT
+    (0xE053, 111), // KEY_DELETE
+    (0xE05B, 125), // KEY_LEFTMETA
+    (0xE05C, 126), // KEY_RIGHTMETA
+    (0xE05D, 127), // KEY_COMPOSE
+    (0xE05F, 142), // KEY_SLEEP
+    (0xE065, 217), // KEY_SEARCH
+    (0xE066, 364), // KEY_FAVORITES
+    (0xE067, 173), // KEY_REFRESH
+    (0xE068, 128), // KEY_STOP
+    (0xE069, 159), // KEY_FORWARD
+    (0xE06A, 158), // KEY_BACK
+    (0xE06B, 157), // KEY_COMPUTER
+    (0xE06C, 155), // KEY_MAIL
+    (0xE06D, 226), // KEY_MEDIA
+];
+
+pub fn scan_code_to_key_code(scan_code: u32) -> Option<u16> {
+    if let Some(&(_, code)) = SCAN_CODE_EXCEPTIONS.iter().find(|(scan, _)| *scan == scan_code) {
+        return Some(code);
+    }
+    
+    match scan_code {
+        0x01..=0x58 => Some(scan_code as u16),
+        _ if scan_code & RAW_KEY_CODE_FLAG != 0 => Some((scan_code & 0xFFFF) as u16),
+        _ => None,
+    }
+}
+
+pub fn key_code_to_scan_code(key_code: u16) -> u32 {
+    if let Some(&(scan, _)) = SCAN_CODE_EXCEPTIONS.iter().find(|(_, code)| *code == key_code) {
+        return scan;
+    }
+    
+    match key_code {
+        0x01..=0x58 => key_code as u32,
+        _ => RAW_KEY_CODE_FLAG | key_code as u32,
+    }
+}
+
+// Linux 只报告区分左右的修饰键，与扫描码查找的规则一致
+pub fn find_key_by_key_code(key_code: u16) -> Option<KeyDef> {
+    find_key_by_scan_code(key_code_to_scan_code(key_code))
+}
+
+// 通过 uinput 虚拟键盘输出按键
+pub struct UinputSink {
+    device: VirtualDevice,
+}
+
+impl UinputSink {
+    pub fn new(keys: &AttributeSet<Key>) -> Result<Self, String> {
+        let device = VirtualDeviceBuilder::new()
+            .and_then(|builder| builder.name(VIRTUAL_DEVICE_NAME).with_keys(keys))
+            .and_then(|builder| builder.build())
+            .map_err(|e| format!("Failed to create uinput device (is /dev/uinput writable?): {}", e))?;
+        
+        Ok(Self { device })
+    }
+    
+    // 原样转发未被拦截的按键事件，value 为 0/1/2（释放/按下/重复）
+    pub fn emit_raw(&mut self, key_code: u16, value: i32) -> Result<(), String> {
+        self.device
+            .emit(&[InputEvent::new(EventType::KEY, key_code, value)])
+            .map_err(|e| format!("Failed to emit key event: {}", e))
+    }
+}
+
+impl OutputSink for UinputSink {
+    fn send_key(&mut self, key_def: &KeyDef, direction: Direction) -> Result<(), String> {
+        let key_code = scan_code_to_key_code(key_def.scan_code)
+            .ok_or_else(|| format!("Key {} has no Linux key code", key_def.name))?;
+        
+        let value = match direction {
+            Direction::Up => 0,
+            Direction::Down => 1,
+        };
+        self.emit_raw(key_code, value)
+    }
+    
+    // 扫描码由 key_code_to_scan_code 得到，总能还原出原始的 KEY_* 码
+    fn replay_key(&mut self, _virt_code: u32, scan_code: u32, direction: Direction) -> Result<(), String> {
+        let key_code = scan_code_to_key_code(scan_code)
+            .ok_or_else(|| format!("Cannot replay scan code 0x{:X}", scan_code))?;
+        
+        let value = match direction {
+            Direction::Up => 0,
+            Direction::Down => 1,
+        };
+        self.emit_raw(key_code, value)
+    }
+}
+
+fn is_keyboard(device: &Device) -> bool {
+    device.supported_keys().is_some_and(|keys| {
+        keys.contains(Key::KEY_A) && keys.contains(Key::KEY_Z) && keys.contains(Key::KEY_ENTER)
+    })
+}
+
+// device 可以是设备路径，也可以是设备名称的一部分；未配置时选择第一个键盘
+fn open_device(selector: Option<&str>) -> Result<Device, String> {
+    if let Some(selector) = selector {
+        if selector.starts_with('/') {
+            return Device::open(selector)
+                .map_err(|e| format!("Cannot open input device '{}': {}", selector, e));
+        }
+    }
+    
+    let mut devices = evdev::enumerate()
+        .filter(|(_, device)| device.name() != Some(VIRTUAL_DEVICE_NAME));
+    
+    let found = match selector {
+        Some(selector) => devices.find(|(_, device)| {
+            device.name().is_some_and(|name| name.to_lowercase().contains(&selector.to_lowercase()))
+        }),
+        None => devices.find(|(_, device)| is_keyboard(device)),
+    };
+    
+    match (found, selector) {
+        (Some((path, device)), _) => {
+            println!("Using input device {} ({})", path.display(), device.name().unwrap_or("unnamed"));
+            Ok(device)
+        }
+        (None, Some(selector)) => Err(format!("No input device matching '{}' found", selector)),
+        (None, None) => Err("No keyboard found in /dev/input (are you in the 'input' group?)".to_string()),
+    }
+}
+
+// 重新加载配置时不会重新选择输入设备
+pub fn linux_main(config: Config, config_path: PathBuf) -> Result<(), Box<dyn std::error::Error>> {
+    let mut device = open_device(config.device.as_deref())?;
+    
+    // 虚拟键盘需要支持原设备的所有按键以及所有可能的重映射输出，
+    // 包括重新加载后的配置中用 sc:0x.. 指定、不在键表中的键
+    let mut keys = AttributeSet::<Key>::new();
+    if let Some(supported) = device.supported_keys() {
+        for key in supported.iter() {
+            keys.insert(key);
+        }
+    }
+    for key_code in (0x01..=0x58).chain(SCAN_CODE_EXCEPTIONS.iter().map(|&(_, code)| code)) {
+        keys.insert(Key::new(key_code));
+    }
+    
+    let sink = UinputSink::new(&keys)?;
+    let mut manager = RemapManager::new(config, sink);
+    
+    // 等待启动时按下的键（通常是回车）释放，否则它会在虚拟键盘上卡住
+    thread::sleep(Duration::from_millis(300));
+    device.grab()
+        .map_err(|e| format!("Failed to grab input device: {}", e))?;
+    
+    // 读取线程阻塞在设备上，主线程按超时轮询以便检查按住超时
+    let (sender, receiver) = mpsc::channel();
+    thread::spawn(move || loop {
+        match device.fetch_events() {
+            Ok(events) => {
+                for event in events {
+                    if let InputEventKind::Key(key) = event.kind() {
+                        if sender.send((key.code(), event.value())).is_err() {
+                            return;
+                        }
+                    }
+                }
+            }
+            Err(e) => {
+                println!("Failed to read input device: {}", e);
+                return;
+            }
+        }
+    });
+    
+    println!("Key remapping started. Press Ctrl+C to exit.");
+    
+    let mut watcher = ConfigWatcher::new(config_path);
+    loop {
+        match receiver.recv_timeout(Duration::from_millis(10)) {
+            Ok((key_code, value)) => {
+                let direction = if value == 0 { Direction::Up } else { Direction::Down };
+                let virt_code = find_key_by_key_code(key_code).map_or(0, |key| key.virt_code);
+                let scan_code = key_code_to_scan_code(key_code);
+                
+                let block_input = manager.handle_input(virt_code, scan_code, direction, InputSource::Physical, timestamp_ms());
+                if !block_input {
+                    manager.output_mut().emit_raw(key_code, value)?;
+                }
+            }
+            Err(RecvTimeoutError::Timeout) => manager.tick(timestamp_ms()),
+            Err(RecvTimeoutError::Disconnected) => break,
+        }
+        watcher.poll(&mut manager, timestamp_ms());
+    }
+    
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::keys::{find_key_by_name, KEYS};
+    
+    #[test]
+    fn key_codes_round_trip_through_scan_codes() {
+        for key_def in KEYS.iter().filter(|key| key.scan_code != 0) {
+            let key_code = scan_code_to_key_code(key_def.scan_code).unwrap();
+            assert_eq!(key_code_to_scan_code(key_code), key_def.scan_code, "{}", key_def.name);
+        }
+        
+        let prog1 = Key::KEY_PROG1.code();
+        assert_eq!(scan_code_to_key_code(key_code_to_scan_code(prog1)), Some(prog1));
+        
+        assert_eq!(key_code_to_scan_code(Key::KEY_RO.code()), 0x73);
+        assert_eq!(key_code_to_scan_code(Key::KEY_YEN.code()), 0x7D);
+        assert_eq!(scan_code_to_key_code(0x71), Some(Key::KEY_HANJA.code()));
+        assert_eq!(key_code_to_scan_code(Key::KEY_HANJA.code()), 0xF1);
+        assert_eq!(scan_code_to_key_code(0x5A), None);
+    }
+    
+    #[test]
+    fn key_code_lookup_prefers_sided_modifiers() {
+        assert_eq!(find_key_by_key_code(Key::KEY_CAPSLOCK.code()), find_key_by_name("CAPSLOCK"));
+        assert_eq!(find_key_by_key_code(Key::KEY_LEFTCTRL.code()), find_key_by_name("LCTRL"));
+        assert_eq!(find_key_by_key_code(Key::KEY_RIGHTALT.code()), find_key_by_name("RALT"));
+        assert_eq!(find_key_by_key_code(Key::KEY_UP.code()), find_key_by_name("UP"));
+        assert_eq!(find_key_by_key_code(Key::KEY_F24.code()), find_key_by_name("F24"));
+        assert_eq!(find_key_by_key_code(Key::KEY_KPENTER.code()), find_key_by_name("NUMPAD_ENTER"));
+        assert_eq!(find_key_by_key_code(Key::KEY_NUMLOCK.code()), find_key_by_name("NUMLOCK"));
+        assert_eq!(find_key_by_key_code(Key::KEY_PAUSE.code()), find_key_by_name("PAUSE"));
+        assert_eq!(find_key_by_key_code(Key::KEY_PROG1.code()), None);
+    }
+}
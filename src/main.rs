@@ -1,16 +1,30 @@
+mod cli;
 mod config;
+mod debug_log;
+mod error;
 mod input;
 mod keys;
+#[cfg(target_os = "linux")]
+mod linux;
 mod remap;
+#[cfg(test)]
+mod replay;
+#[cfg(any(target_os = "windows", target_os = "linux", test))]
+mod watcher;
 
-use config::load_config;
+use cli::Command;
+use config::{load_config, Config};
+#[cfg(not(target_os = "linux"))]
 use remap::RemapManager;
 use std::env;
 use std::path::PathBuf;
+use std::process;
+#[cfg(target_os = "windows")]
 use std::sync::Mutex;
 
 // 全局状态
-static REMAP_MANAGER: Mutex<Option<RemapManager>> = Mutex::new(None);
+#[cfg(target_os = "windows")]
+static REMAP_MANAGER: Mutex<Option<RemapManager<input::SendInputSink>>> = Mutex::new(None);
 
 fn get_config_path() -> Result<PathBuf, String> {
     let exe_path = env::current_exe()
@@ -19,20 +33,128 @@ fn get_config_path() -> Result<PathBuf, String> {
     let exe_dir = exe_path.parent()
         .ok_or("Failed to get executable directory")?;
     
+    // 优先使用 config.toml，否则使用旧格式的 config.txt
+    let toml_path = exe_dir.join("config.toml");
+    if toml_path.exists() {
+        return Ok(toml_path);
+    }
+    
     Ok(exe_dir.join("config.txt"))
 }
 
+fn print_config(config: &Config) {
+    for warning in &config.warnings {
+        println!("{}", warning.warning());
+    }
+    
+    if config.match_by == config::MatchBy::ScanCode {
+        println!("Matching keys by scan code");
+    }
+    if config.foreign_injected != config::ForeignInjected::Other {
+        println!("Input injected by other programs: {}", config.foreign_injected);
+    }
+    println!("Number of remaps: {}", config.remaps.len());
+    
+    for (i, remap) in config.remaps.iter().enumerate() {
+        println!("Remap {}: {} -> {} (alone) / {} (with other)", 
+                 i + 1,
+                 remap.from.name,
+                 remap.to_when_alone,
+                 remap.to_with_other);
+        
+        if let Some(description) = keys::describe_input_match(&remap.from) {
+            println!("    {}", description);
+        }
+        
+        // 合并全局默认值之后的选项
+        let mut options = Vec::new();
+        if let Some(timeout) = remap.hold_timeout_ms {
+            options.push(format!("hold_timeout_ms={}", timeout));
+        }
+        if let Some(hold_mode) = remap.hold_mode {
+            options.push(format!("hold_mode={}", hold_mode));
+        }
+        if let Some(quick_tap) = remap.quick_tap_ms {
+            options.push(format!("quick_tap_ms={}", quick_tap));
+        }
+        if let Some(retro_tap) = remap.retro_tap {
+            options.push(format!("retro_tap={}", retro_tap));
+        }
+        if !options.is_empty() {
+            println!("    {}", options.join(", "));
+        }
+    }
+    
+    for layer in &config.layers {
+        println!("Layer {}: {} mappings", layer.name, layer.mappings.len());
+        for (from, to) in &layer.mappings {
+            println!("    {} -> {}", from.name, to.name);
+        }
+    }
+}
+
+fn list_keys() {
+    for key_def in keys::KEYS {
+        println!("{:<20} vk:0x{:02X} sc:0x{:02X}", key_def.name, key_def.virt_code, key_def.scan_code);
+    }
+    
+    println!("\nAliases:");
+    for (alias, name) in keys::KEY_ALIASES {
+        println!("{:<20} {}", alias, name);
+    }
+}
+
+// 只解析配置，出错时以非零状态退出，便于在脚本中使用
+fn check(config_path: PathBuf) {
+    match load_config(&config_path) {
+        Ok(config) => {
+            println!("{}: OK", config_path.display());
+            print_config(&config);
+        }
+        Err(e) => {
+            eprintln!("{}: {}", config_path.display(), e);
+            process::exit(1);
+        }
+    }
+}
+
 fn main() -> Result<(), Box<dyn std::error::Error>> {
+    let args = match cli::parse_args(env::args().skip(1)) {
+        Ok(args) => args,
+        Err(e) => {
+            eprintln!("{}\n\n{}", e, cli::USAGE);
+            process::exit(2);
+        }
+    };
+    
+    let explicit_config = args.config_path.is_some();
+    let config_path = match args.config_path {
+        Some(path) => path,
+        None => get_config_path()?,
+    };
+    
+    match args.command {
+        Command::Help => println!("{}", cli::USAGE),
+        Command::ListKeys => list_keys(),
+        Command::Check => check(config_path),
+        Command::Run => run(config_path, explicit_config)?,
+    }
+    
+    Ok(())
+}
+
+fn run(config_path: PathBuf, explicit_config: bool) -> Result<(), Box<dyn std::error::Error>> {
     println!("Dual Key Remap - Rust Version");
     println!("==============================");
     
     // 加载配置
-    let config_path = get_config_path()?;
     let config = match load_config(&config_path) {
         Ok(config) => config,
         Err(e) => {
             println!("Error loading config: {}", e);
-            println!("Make sure config.txt exists in the same directory as the executable.");
+            if !explicit_config {
+                println!("Make sure config.toml or config.txt exists in the same directory as the executable.");
+            }
             println!("\nPress Enter to exit...");
             let _ = std::io::stdin().read_line(&mut String::new());
             return Ok(());
@@ -40,32 +162,35 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
     };
     
     println!("Configuration loaded successfully!");
-    println!("Number of remaps: {}", config.remaps.len());
-    
-    for (i, remap) in config.remaps.iter().enumerate() {
-        println!("Remap {}: {} -> {} (alone) / {} (with other)", 
-                 i + 1,
-                 remap.from.name,
-                 remap.to_when_alone.name,
-                 remap.to_with_other.name);
-    }
-    
-    // 创建重映射管理器
-    let manager = RemapManager::new(config.remaps);
-    *REMAP_MANAGER.lock().unwrap() = Some(manager);
-    
-    println!("\nNote: This is a demonstration version running on macOS.");
-    println!("The actual key remapping functionality requires Windows APIs");
-    println!("and should be compiled and run on Windows.");
+    print_config(&config);
     
     #[cfg(target_os = "windows")]
     {
+        // debug 日志输出到控制台时不隐藏控制台窗口
+        let keep_console = config.debug && config.log_file.is_none();
+        
+        // 创建重映射管理器
+        let manager = RemapManager::new(config, input::SendInputSink);
+        *REMAP_MANAGER.lock().unwrap() = Some(manager);
+        
         println!("\nStarting Windows key remapping...");
-        windows_main()?;
+        windows_main(config_path, keep_console)?;
+    }
+    
+    #[cfg(target_os = "linux")]
+    {
+        println!("\nStarting Linux key remapping...");
+        linux::linux_main(config, config_path)?;
     }
     
-    #[cfg(not(target_os = "windows"))]
+    #[cfg(not(any(target_os = "windows", target_os = "linux")))]
     {
+        let _manager = RemapManager::new(config, input::StdoutSink);
+        
+        println!("\nNote: This is a demonstration version.");
+        println!("The actual key remapping functionality requires Windows or Linux");
+        println!("and should be compiled and run there.");
+        
         println!("\nSimulating key remapping behavior...");
         println!("Press Enter to exit...");
         let _ = std::io::stdin().read_line(&mut String::new());
@@ -75,8 +200,8 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
 }
 
 #[cfg(target_os = "windows")]
-fn windows_main() -> Result<(), Box<dyn std::error::Error>> {
-    use input::{Direction, MOUSE_DUMMY_VK};
+fn windows_main(config_path: PathBuf, keep_console: bool) -> Result<(), Box<dyn std::error::Error>> {
+    use input::{timestamp_ms, Direction, InputSource, INJECTED_KEY_ID, MOUSE_DUMMY_VK};
     use windows::core::*;
     use windows::Win32::Foundation::*;
     use windows::Win32::System::Console::*;
@@ -85,7 +210,6 @@ fn windows_main() -> Result<(), Box<dyn std::error::Error>> {
     use windows::Win32::UI::Input::KeyboardAndMouse::*;
     use windows::Win32::UI::WindowsAndMessaging::*;
     
-    static INJECTED_KEY_ID: usize = 0xFFC3CED7;
     static mut KEYBOARD_HOOK: HHOOK = HHOOK(0);
     static mut MOUSE_HOOK: HHOOK = HHOOK(0);
     
@@ -98,14 +222,29 @@ fn windows_main() -> Result<(), Box<dyn std::error::Error>> {
                 _ => return CallNextHookExW(KEYBOARD_HOOK, code, wparam, lparam),
             };
             
-            let is_injected = kb_struct.dwExtraInfo == INJECTED_KEY_ID;
+            // LLKHF_INJECTED 标记所有注入的按键，dwExtraInfo 区分是否为我们自己注入的
+            let source = if (kb_struct.flags & LLKHF_INJECTED).0 == 0 {
+                InputSource::Physical
+            } else if kb_struct.dwExtraInfo == INJECTED_KEY_ID {
+                InputSource::Own
+            } else {
+                InputSource::Foreign
+            };
+            // 扩展键的扫描码加上 0xE0 前缀，与 KeyDef::scan_code 一致
+            let scan_code = if (kb_struct.flags & LLKHF_EXTENDED).0 != 0 {
+                0xE000 | kb_struct.scanCode
+            } else {
+                kb_struct.scanCode
+            };
             
             if let Ok(mut manager_guard) = REMAP_MANAGER.lock() {
                 if let Some(ref mut manager) = *manager_guard {
                     let block_input = manager.handle_input(
                         kb_struct.vkCode,
+                        scan_code,
                         direction,
-                        is_injected,
+                        source,
+                        timestamp_ms(),
                     );
                     
                     if block_input {
@@ -123,9 +262,16 @@ fn windows_main() -> Result<(), Box<dyn std::error::Error>> {
             match wparam.0 as u32 {
                 WM_MOUSEWHEEL | WM_LBUTTONDOWN | WM_RBUTTONDOWN | WM_MBUTTONDOWN 
                 | WM_XBUTTONDOWN | WM_NCXBUTTONDOWN => {
+                    let mouse_struct = *(lparam.0 as *const MSLLHOOKSTRUCT);
+                    let source = if mouse_struct.flags & LLMHF_INJECTED == 0 {
+                        InputSource::Physical
+                    } else {
+                        InputSource::Foreign
+                    };
+                    
                     if let Ok(mut manager_guard) = REMAP_MANAGER.lock() {
                         if let Some(ref mut manager) = *manager_guard {
-                            let block_input = manager.handle_input(MOUSE_DUMMY_VK, Direction::Down, false);
+                            let block_input = manager.handle_input(MOUSE_DUMMY_VK, 0, Direction::Down, source, timestamp_ms());
                             
                             if block_input {
                                 return LRESULT(1);
@@ -188,12 +334,30 @@ fn windows_main() -> Result<(), Box<dyn std::error::Error>> {
     println!("Key remapping started. Press Ctrl+C to exit.");
     
     // 隐藏控制台窗口
-    FreeConsole()?;
+    if !keep_console {
+        FreeConsole()?;
+    }
+    
+    // 定时器用于在没有输入时检查按住超时，以及检查配置文件是否修改
+    unsafe {
+        SetTimer(None, 0, 10, None);
+    }
+    let mut watcher = watcher::ConfigWatcher::new(config_path);
     
     // 消息循环
     unsafe {
         let mut msg = MSG::default();
         while GetMessageW(&mut msg, None, 0, 0).as_bool() {
+            if msg.message == WM_TIMER {
+                if let Ok(mut manager_guard) = REMAP_MANAGER.lock() {
+                    if let Some(ref mut manager) = *manager_guard {
+                        manager.tick(timestamp_ms());
+                        watcher.poll(manager, timestamp_ms());
+                    }
+                }
+                continue;
+            }
+            
             TranslateMessage(&msg);
             DispatchMessageW(&msg);
         }
@@ -1,18 +1,31 @@
-use crate::config::RemapConfig;
-use crate::input::{send_input, Direction};
-use std::collections::HashMap;
+use crate::config::{Config, ForeignInjected, HoldMode, MatchBy, RemapConfig, Stroke, WithOther};
+use crate::debug_log::{DebugLog, LoggedSink};
+use crate::input::{Direction, InputSource, OutputSink, MOUSE_DUMMY_VK};
+use crate::keys::{generic_modifier, sided_modifiers, KeyDef, KEYS};
+use std::collections::{HashMap, HashSet};
+
+// balanced 模式未设置 hold_timeout_ms 时使用的 tapping term（毫秒）
+const DEFAULT_TAPPING_TERM_MS: u64 = 200;
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum State {
     Idle,
     HeldDownAlone,
     HeldDownWithOther,
+    // 快速连按后按住，when_alone 的最后一个组合键保持按下
+    QuickTapHeld,
 }
 
 #[derive(Debug)]
 pub struct Remap {
     pub config: RemapConfig,
     pub state: State,
+    // 进入 HeldDownAlone 的时间（毫秒）
+    pub pressed_at: u64,
+    // 上次单击（发送 when_alone）释放的时间，用于判断 quick_tap_ms
+    pub tapped_at: Option<u64>,
+    // 按住期间是否有过其他按键或鼠标输入，用于 retro_tap
+    pub interrupted: bool,
 }
 
 impl Remap {
@@ -20,87 +33,1305 @@ impl Remap {
         Self {
             config,
             state: State::Idle,
+            pressed_at: 0,
+            tapped_at: None,
+            interrupted: false,
         }
     }
+    
+    fn hold_mode(&self) -> HoldMode {
+        self.config.hold_mode.unwrap_or_default()
+    }
+    
+    // 尚未决定 when_alone 还是 with_other，其间的其他按键需要缓冲
+    fn is_deciding(&self) -> bool {
+        self.state == State::HeldDownAlone && self.hold_mode() != HoldMode::HoldOnOtherPress
+    }
+    
+    fn hold_timeout_ms(&self) -> Option<u64> {
+        match self.hold_mode() {
+            HoldMode::Balanced => Some(self.config.hold_timeout_ms.unwrap_or(DEFAULT_TAPPING_TERM_MS)),
+            _ => self.config.hold_timeout_ms,
+        }
+    }
+    
+    fn is_quick_tap(&self, time: u64) -> bool {
+        match (self.config.quick_tap_ms, self.tapped_at) {
+            (Some(quick_tap), Some(tapped_at)) => time.saturating_sub(tapped_at) < quick_tap,
+            _ => false,
+        }
+    }
+    
+    fn hold_timed_out(&self, time: u64) -> bool {
+        match self.hold_timeout_ms() {
+            Some(timeout) => self.state == State::HeldDownAlone && time.saturating_sub(self.pressed_at) >= timeout,
+            None => false,
+        }
+    }
+    
+    fn set_state(&mut self, state: State, log: Option<&mut DebugLog>) {
+        if let Some(log) = log {
+            if state != self.state {
+                log.write(format_args!("state  {} {:?} -> {:?}", self.config.from.name, self.state, state));
+            }
+        }
+        self.state = state;
+    }
+}
+
+// 按住输出键的来源：重映射键或通过层按下的键，都以按 match_by 得到的键码标识
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Holder {
+    Remap(u32),
+    LayerKey(u32),
 }
 
-pub struct RemapManager {
+pub struct RemapManager<S: OutputSink> {
+    // remaps 和 layers 的键是按 match_by 得到的键码（虚拟键码或扫描码）
+    match_by: MatchBy,
+    foreign_injected: ForeignInjected,
     remaps: HashMap<u32, Remap>,
+    // 层名 -> (键码 -> 输出键)
+    layers: HashMap<String, HashMap<u32, KeyDef>>,
+    // 按激活顺序排列的层，最后一个生效
+    active_layers: Vec<String>,
+    // 通过层按下、尚未释放的键：按 match_by 得到的键码 -> 输出键
+    layer_keys_down: HashMap<u32, KeyDef>,
+    // 按下时没有经过层映射、直接放行且尚未释放的键，之后的自动重复和释放同样放行
+    passed_keys_down: HashSet<u32>,
+    // 逻辑上按住的输出键：output_id -> (实际按下的键, 按住它的来源)。第一个来源按住时发送按下，
+    // 最后一个松开后才释放同一个键
+    held_outputs: HashMap<u32, (KeyDef, Vec<Holder>)>,
+    // 双功能键未决定时被拦截的按键 (虚拟键码, 扫描码, 方向, 是否参与重映射, 时间)，决定后按原顺序重新处理
+    pending: Vec<(u32, u32, Direction, bool, u64)>,
+    // debug 模式下 output 同时记录输入、状态切换和输出
+    output: LoggedSink<S>,
 }
 
-impl RemapManager {
-    pub fn new(configs: Vec<RemapConfig>) -> Self {
-        let mut remaps = HashMap::new();
+impl<S: OutputSink> RemapManager<S> {
+    pub fn new(config: Config, output: S) -> Self {
+        let mut manager = Self {
+            match_by: MatchBy::default(),
+            foreign_injected: ForeignInjected::default(),
+            remaps: HashMap::new(),
+            layers: HashMap::new(),
+            active_layers: Vec::new(),
+            layer_keys_down: HashMap::new(),
+            passed_keys_down: HashSet::new(),
+            held_outputs: HashMap::new(),
+            pending: Vec::new(),
+            output: LoggedSink { sink: output, log: None },
+        };
+        manager.reload(config);
+        manager
+    }
+    
+    // 替换配置；只应在 is_idle() 时调用，否则按住的键在新配置下无法正确释放
+    pub fn reload(&mut self, config: Config) {
+        // 打开日志失败时退回到控制台
+        self.output.log = match config.debug {
+            true => Some(DebugLog::open(config.log_file.as_deref()).unwrap_or_else(|e| {
+                println!("{}", e);
+                DebugLog::new(Box::new(std::io::stdout()))
+            })),
+            false => None,
+        };
+        
+        let match_by = config.match_by;
+        self.match_by = match_by;
+        self.foreign_injected = config.foreign_injected;
+        
+        self.remaps = config.remaps.into_iter()
+            .map(|remap_config| (match_by.key_id(&remap_config.from), Remap::new(remap_config)))
+            .collect();
+        
+        self.layers = config.layers.into_iter()
+            .map(|layer| {
+                let mappings = layer.mappings.into_iter()
+                    .map(|(from, to)| (match_by.key_id(&from), to))
+                    .collect();
+                (layer.name, mappings)
+            })
+            .collect();
+    }
+    
+    // 没有按住的重映射键、激活的层、按住的输出键或缓冲的按键
+    #[cfg(any(target_os = "windows", target_os = "linux", test))]
+    pub fn is_idle(&self) -> bool {
+        self.remaps.values().all(|remap| remap.state == State::Idle)
+            && self.active_layers.is_empty()
+            && self.layer_keys_down.is_empty()
+            && self.held_outputs.is_empty()
+            && self.pending.is_empty()
+    }
+    
+    #[cfg(any(target_os = "linux", test))]
+    pub fn output_mut(&mut self) -> &mut S {
+        &mut self.output.sink
+    }
+    
+    // 测试中把日志写入内存
+    #[cfg(test)]
+    pub fn set_log(&mut self, log: Option<DebugLog>) {
+        self.output.log = log;
+    }
+    
+    // scan_code 带 0xE0 前缀表示扩展键；time 为单调递增的毫秒时间戳，用于判断按住超时。
+    // 其他系统上的演示版本只创建 RemapManager 而不输入按键
+    #[cfg_attr(not(any(target_os = "windows", target_os = "linux")), allow(dead_code))]
+    pub fn handle_input(&mut self, virt_code: u32, scan_code: u32, direction: Direction, source: InputSource, time: u64) -> bool {
+        if let Some(ref mut log) = self.output.log {
+            log.set_time(time);
+            log.input(virt_code, scan_code, direction, source);
+        }
+        self.tick(time);
+        
+        let remappable = match source {
+            InputSource::Physical => true,
+            // 自己注入的输出（包括 with_other 的按下）不能再被当作其他按键
+            InputSource::Own => return false,
+            InputSource::Foreign => match self.foreign_injected {
+                ForeignInjected::Ignore => return false,
+                ForeignInjected::Other => false,
+                ForeignInjected::Remap => true,
+            },
+        };
+        
+        if virt_code == MOUSE_DUMMY_VK {
+            // 鼠标点击无法缓冲重放，直接决定所有未决定的键
+            self.mark_interrupted();
+            self.event_other_input();
+            self.commit_deciding(|_| true);
+            false
+        } else {
+            self.dispatch(virt_code, scan_code, direction, remappable, time)
+        }
+    }
+    
+    // 不参与重映射的输入（foreign_injected=other 时其他软件注入的按键）只作为其他按键，
+    // 同样会被缓冲以保持顺序，但不按重映射键或层处理
+    fn dispatch(&mut self, virt_code: u32, scan_code: u32, direction: Direction, remappable: bool, time: u64) -> bool {
+        match find_key(&self.remaps, self.match_by, virt_code, scan_code).filter(|_| remappable) {
+            Some(remap_key) => self.handle_remapped_key(remap_key, virt_code, scan_code, direction, time),
+            None => self.handle_other_key(virt_code, scan_code, direction, remappable, time),
+        }
+    }
+    
+    fn handle_other_key(&mut self, virt_code: u32, scan_code: u32, direction: Direction, remappable: bool, time: u64) -> bool {
+        if direction == Direction::Down {
+            self.mark_interrupted();
+        }
+        
+        if self.buffer_input(virt_code, scan_code, direction, remappable, time, None) {
+            return true;
+        }
+        self.event_other_input();
+        remappable && self.handle_layer_key(virt_code, scan_code, direction)
+    }
+    
+    // 其他双功能键未决定时，重映射键和普通按键一样先缓冲，决定后再进入自己的状态机，
+    // 这样同时按住的双功能键按按下顺序依次决定
+    fn handle_remapped_key(&mut self, remap_key: u32, virt_code: u32, scan_code: u32, direction: Direction, time: u64) -> bool {
+        if direction == Direction::Down && self.remaps.get(&remap_key).is_some_and(|remap| remap.state == State::Idle) {
+            self.mark_interrupted();
+        }
+        
+        if self.buffer_input(virt_code, scan_code, direction, true, time, Some(remap_key)) {
+            return true;
+        }
+        match direction {
+            Direction::Down => self.handle_remapped_key_down(remap_key, time),
+            Direction::Up => self.handle_remapped_key_up(remap_key, time),
+        }
+    }
+    
+    // 有未决定的键时缓冲按键，返回是否已缓冲；remap_key 是按键自己对应的重映射键，它不等待自己的决定
+    fn buffer_input(
+        &mut self,
+        virt_code: u32,
+        scan_code: u32,
+        direction: Direction,
+        remappable: bool,
+        time: u64,
+        remap_key: Option<u32>,
+    ) -> bool {
+        let deciding = self.remaps.iter()
+            .any(|(key, remap)| Some(*key) != remap_key && remap.is_deciding());
+        
+        // 未决定期间的按键先缓冲；之前就按下的键的释放不影响决定，直接放行
+        let key_id = self.match_by.input_id(virt_code, scan_code);
+        let pressed_while_deciding = self.pending.iter()
+            .any(|&(virt, scan, dir, _, _)| self.match_by.input_id(virt, scan) == key_id && dir == Direction::Down);
+        let buffer = deciding && (direction == Direction::Down || pressed_while_deciding || !self.pending.is_empty());
         
-        for config in configs {
-            let virt_code = config.from.virt_code;
-            remaps.insert(virt_code, Remap::new(config));
+        if !buffer {
+            return false;
         }
         
-        Self { remaps }
+        if direction == Direction::Down {
+            self.event_other_input();
+        }
+        self.pending.push((virt_code, scan_code, direction, remappable, time));
+        
+        // permissive_hold：另一个键在按住期间完整地按下并释放，决定为 with_other
+        if direction == Direction::Up && pressed_while_deciding {
+            self.commit_deciding(|remap| remap.hold_mode() == HoldMode::PermissiveHold);
+        }
+        true
     }
     
-    pub fn handle_input(&mut self, virt_code: u32, direction: Direction, is_injected: bool) -> bool {
-        if is_injected {
-            self.event_other_input()
-        } else if self.remaps.contains_key(&virt_code) {
-            // 处理重映射的键
-            match direction {
-                Direction::Down => self.handle_remapped_key_down(virt_code),
-                Direction::Up => self.handle_remapped_key_up(virt_code),
+    // 将满足条件的未决定键切换为 with_other，然后重放缓冲的按键
+    fn commit_deciding(&mut self, filter: impl Fn(&Remap) -> bool) {
+        let to_commit: Vec<u32> = self.remaps.iter()
+            .filter(|(_, remap)| remap.is_deciding() && filter(remap))
+            .map(|(virt_code, _)| *virt_code)
+            .collect();
+        
+        if to_commit.is_empty() {
+            return;
+        }
+        
+        for virt_code in to_commit {
+            self.press_with_other(virt_code);
+        }
+        self.flush_pending();
+    }
+    
+    // 按原顺序重新处理缓冲的按键；仍有未决定的键时它们会再次进入缓冲
+    fn flush_pending(&mut self) {
+        for (virt_code, scan_code, direction, remappable, time) in std::mem::take(&mut self.pending) {
+            if !self.dispatch(virt_code, scan_code, direction, remappable, time) {
+                let _ = self.output.replay_key(virt_code, scan_code, direction);
             }
-        } else {
-            self.event_other_input()
         }
     }
     
-    fn handle_remapped_key_down(&mut self, virt_code: u32) -> bool {
+    // 定时调用，使按住超过 hold_timeout_ms 的键在没有其他输入时也切换为 with_other
+    pub fn tick(&mut self, time: u64) {
+        if let Some(ref mut log) = self.output.log {
+            log.set_time(time);
+        }
+        
+        // 重放的缓冲按键中可能有按下时间更早、同样已经超时的双功能键
+        loop {
+            let timed_out: Vec<u32> = self.remaps.iter()
+                .filter(|(_, remap)| remap.hold_timed_out(time))
+                .map(|(virt_code, _)| *virt_code)
+                .collect();
+            
+            if timed_out.is_empty() {
+                return;
+            }
+            
+            for virt_code in timed_out {
+                self.press_with_other(virt_code);
+            }
+            self.flush_pending();
+        }
+    }
+    
+    fn press_with_other(&mut self, virt_code: u32) {
         if let Some(remap) = self.remaps.get_mut(&virt_code) {
-            if remap.state == State::Idle {
-                remap.state = State::HeldDownAlone;
+            remap.set_state(State::HeldDownWithOther, self.output.log.as_mut());
+            let keys = match remap.config.to_with_other {
+                WithOther::Chord(ref keys) => keys.clone(),
+                WithOther::Layer(ref name) => {
+                    self.active_layers.push(name.clone());
+                    Vec::new()
+                }
+            };
+            
+            for key_def in &keys {
+                self.hold_output(key_def, Holder::Remap(virt_code));
+            }
+        }
+    }
+    
+    fn release_with_other(&mut self, virt_code: u32) {
+        if let Some(remap) = self.remaps.get_mut(&virt_code) {
+            remap.set_state(State::Idle, self.output.log.as_mut());
+            let keys = match remap.config.to_with_other {
+                WithOther::Chord(ref keys) => keys.clone(),
+                WithOther::Layer(ref name) => {
+                    if let Some(index) = self.active_layers.iter().rposition(|layer| layer == name) {
+                        self.active_layers.remove(index);
+                    }
+                    Vec::new()
+                }
+            };
+            
+            // 逆序释放
+            for key_def in keys.iter().rev() {
+                self.release_output(key_def, Holder::Remap(virt_code));
+            }
+        }
+    }
+    
+    // 按住输出键；已被其他来源按住时只记录来源。同一来源再次按下（自动重复）时照常发送
+    fn hold_output(&mut self, key_def: &KeyDef, holder: Holder) {
+        let (pressed, holders) = self.held_outputs.entry(output_id(key_def)).or_insert((*key_def, Vec::new()));
+        
+        if holders.is_empty() || holders.contains(&holder) {
+            let _ = self.output.send_key(pressed, Direction::Down);
+        }
+        if !holders.contains(&holder) {
+            holders.push(holder);
+        }
+    }
+    
+    // 松开来源对输出键的按住，没有其他来源按住时才发送释放
+    fn release_output(&mut self, key_def: &KeyDef, holder: Holder) {
+        let id = output_id(key_def);
+        let Some((pressed, holders)) = self.held_outputs.get_mut(&id) else {
+            return;
+        };
+        
+        holders.retain(|&other| other != holder);
+        if holders.is_empty() {
+            let pressed = *pressed;
+            self.held_outputs.remove(&id);
+            let _ = self.output.send_key(&pressed, Direction::Up);
+        }
+    }
+    
+    // 层激活时按层的映射表发送按键；通过层按下的键在释放前一直保持映射
+    fn handle_layer_key(&mut self, virt_code: u32, scan_code: u32, direction: Direction) -> bool {
+        // 与 remap 一样按 match_by 区分按键，如 match_by=scan_code 时的 ENTER 和 NUMPAD_ENTER
+        let key_id = self.match_by.input_id(virt_code, scan_code);
+        if self.passed_keys_down.contains(&key_id) {
+            if direction == Direction::Up {
+                self.passed_keys_down.remove(&key_id);
+            }
+            return false;
+        }
+        
+        let key_def = match direction {
+            Direction::Down => self.layer_keys_down.get(&key_id).copied().or_else(|| {
+                let layer = self.layers.get(self.active_layers.last()?)?;
+                let key = find_key(layer, self.match_by, virt_code, scan_code)?;
+                layer.get(&key).copied()
+            }),
+            Direction::Up => self.layer_keys_down.remove(&key_id),
+        };
+        
+        match key_def {
+            Some(key_def) => {
+                match direction {
+                    Direction::Down => {
+                        self.layer_keys_down.insert(key_id, key_def);
+                        self.hold_output(&key_def, Holder::LayerKey(key_id));
+                    }
+                    Direction::Up => self.release_output(&key_def, Holder::LayerKey(key_id)),
+                }
+                true
+            }
+            None => {
+                if direction == Direction::Down {
+                    self.passed_keys_down.insert(key_id);
+                }
+                false
+            }
+        }
+    }
+    
+    fn handle_remapped_key_down(&mut self, virt_code: u32, time: u64) -> bool {
+        // 另一个双功能键的按下也算其他按键：按住的 hold_on_other_press 键先切换为 with_other，
+        // 同时按住的多个修饰键因此会叠加
+        if self.remaps.get(&virt_code).is_some_and(|remap| remap.state == State::Idle) {
+            self.event_other_input();
+        }
+        
+        if let Some(remap) = self.remaps.get_mut(&virt_code) {
+            match remap.state {
+                State::Idle if remap.is_quick_tap(time) => {
+                    remap.set_state(State::QuickTapHeld, self.output.log.as_mut());
+                    let keys = press_sequence(&mut self.output, &remap.config.to_when_alone.0).unwrap_or_default();
+                    for key_def in &keys {
+                        self.hold_output(key_def, Holder::Remap(virt_code));
+                    }
+                }
+                State::Idle => {
+                    remap.set_state(State::HeldDownAlone, self.output.log.as_mut());
+                    remap.pressed_at = time;
+                    remap.interrupted = false;
+                }
+                State::QuickTapHeld => {
+                    // 系统自动重复产生的按下，重新发送最后一个键以便重复输入
+                    if let Some(Stroke::Chord(keys)) = remap.config.to_when_alone.0.last() {
+                        if let Some(key_def) = keys.last() {
+                            let _ = self.output.send_key(key_def, Direction::Down);
+                        }
+                    }
+                }
+                _ => {}
             }
         }
         true // 阻止原始输入
     }
     
-    fn handle_remapped_key_up(&mut self, virt_code: u32) -> bool {
+    fn handle_remapped_key_up(&mut self, virt_code: u32, time: u64) -> bool {
         if let Some(remap) = self.remaps.get_mut(&virt_code) {
             match remap.state {
                 State::HeldDownWithOther => {
-                    remap.state = State::Idle;
-                    let key_def = remap.config.to_with_other;
-                    let _ = send_input(&key_def, Direction::Up);
+                    // retro_tap：超时后没有其他输入，释放 with_other 后仍发送 when_alone
+                    let retro_tap = remap.config.retro_tap.unwrap_or(false) && !remap.interrupted;
+                    remap.tapped_at = None;
+                    self.release_with_other(virt_code);
+                    
+                    if retro_tap {
+                        if let Some(remap) = self.remaps.get(&virt_code) {
+                            for stroke in &remap.config.to_when_alone.0 {
+                                let _ = send_stroke(&mut self.output, stroke);
+                            }
+                        }
+                    }
+                }
+                State::QuickTapHeld => {
+                    remap.set_state(State::Idle, self.output.log.as_mut());
+                    remap.tapped_at = None;
+                    let keys = match remap.config.to_when_alone.0.last() {
+                        Some(Stroke::Chord(keys)) => keys.clone(),
+                        _ => Vec::new(),
+                    };
+                    for key_def in keys.iter().rev() {
+                        self.release_output(key_def, Holder::Remap(virt_code));
+                    }
                 }
                 _ => {
-                    remap.state = State::Idle;
-                    let key_def = remap.config.to_when_alone;
-                    // 发送单独按键的按下和释放
-                    let _ = send_input(&key_def, Direction::Down);
-                    let _ = send_input(&key_def, Direction::Up);
+                    remap.set_state(State::Idle, self.output.log.as_mut());
+                    remap.tapped_at = Some(time);
+                    // 依次发送单独按下时的按键序列
+                    for stroke in &remap.config.to_when_alone.0 {
+                        let _ = send_stroke(&mut self.output, stroke);
+                    }
+                    // 再重放按住期间缓冲的按键
+                    self.flush_pending();
                 }
             }
         }
         true // 阻止原始输入
     }
     
+    // 按住期间有过其他输入，用于 retro_tap；自己注入的按键在 handle_input 中已被忽略
+    fn mark_interrupted(&mut self) {
+        for remap in self.remaps.values_mut() {
+            if remap.state != State::Idle {
+                remap.interrupted = true;
+            }
+        }
+    }
+    
     fn event_other_input(&mut self) -> bool {
         // 收集需要更新的键
         let mut keys_to_update = Vec::new();
         
         for (virt_code, remap) in &self.remaps {
-            if remap.state == State::HeldDownAlone {
-                keys_to_update.push((*virt_code, remap.config.to_with_other));
+            if remap.state == State::HeldDownAlone && remap.hold_mode() == HoldMode::HoldOnOtherPress {
+                keys_to_update.push(*virt_code);
             }
         }
         
         // 更新状态并发送输入
-        for (virt_code, key_def) in keys_to_update {
-            if let Some(remap) = self.remaps.get_mut(&virt_code) {
-                remap.state = State::HeldDownWithOther;
-                let _ = send_input(&key_def, Direction::Down);
-            }
+        for virt_code in keys_to_update {
+            self.press_with_other(virt_code);
         }
         
         false // 不阻止其他输入
     }
 }
+
+// 在按 match_by 键码索引的表中查找输入对应的键。钩子报告的是区分左右的修饰键，
+// 通用的 CTRL/SHIFT/ALT 匹配左右两侧，LCTRL 等只匹配自己一侧
+fn find_key<T>(map: &HashMap<u32, T>, match_by: MatchBy, virt_code: u32, scan_code: u32) -> Option<u32> {
+    let id = match_by.input_id(virt_code, scan_code);
+    if map.contains_key(&id) {
+        return Some(id);
+    }
+    
+    let generic = generic_modifier(virt_code)?;
+    let generic_key = KEYS.iter().find(|key| key.virt_code == generic)?;
+    Some(match_by.key_id(generic_key)).filter(|id| map.contains_key(id))
+}
+
+// 输出键对应的物理按键：通用的 CTRL/SHIFT/ALT 发送的是左侧键，与 LCTRL 等共用引用计数
+fn output_id(key_def: &KeyDef) -> u32 {
+    sided_modifiers(key_def.virt_code).map_or(key_def.virt_code, |[left, _]| left)
+}
+
+// 组合键按顺序按下、逆序释放；文本直接输入
+fn send_stroke<S: OutputSink>(output: &mut S, stroke: &Stroke) -> Result<(), String> {
+    match stroke {
+        Stroke::Chord(keys) => {
+            for key_def in keys {
+                output.send_key(key_def, Direction::Down)?;
+            }
+            for key_def in keys.iter().rev() {
+                output.send_key(key_def, Direction::Up)?;
+            }
+            Ok(())
+        }
+        Stroke::Text(text) => output.send_text(text),
+    }
+}
+
+// 除最后一个组合键外完整发送，返回需要保持按下的最后一个组合键
+fn press_sequence<S: OutputSink>(output: &mut S, strokes: &[Stroke]) -> Result<Vec<KeyDef>, String> {
+    let Some((last, rest)) = strokes.split_last() else {
+        return Ok(Vec::new());
+    };
+    
+    for stroke in rest {
+        send_stroke(output, stroke)?;
+    }
+    match last {
+        Stroke::Chord(keys) => Ok(keys.clone()),
+        Stroke::Text(text) => output.send_text(text).map(|_| Vec::new()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::RemapManager;
+    use crate::config::parse_config;
+    use crate::input::{Direction, InputSource, RecordedEvent, RecordingSink, MOUSE_DUMMY_VK};
+    use crate::keys::find_key_by_name;
+    use crate::replay::{down, input, replay, up, vk, ScriptedInput};
+    
+    const CAPS_CONFIG: &str = "remap_key=CAPSLOCK\nwhen_alone=ESCAPE\nwith_other=CTRL\n";
+    const CAPS_TIMEOUT_CONFIG: &str = "remap_key=CAPSLOCK\nwhen_alone=ESCAPE\nwith_other=CTRL\nhold_timeout_ms=200\n";
+    
+    struct Case {
+        name: &'static str,
+        config: &'static str,
+        inputs: Vec<ScriptedInput>,
+        outputs: &'static [&'static str],
+        blocked: &'static [bool],
+    }
+    
+    fn run(cases: Vec<Case>) {
+        for case in cases {
+            let result = replay(case.config, &case.inputs);
+            assert_eq!(result.output_names(), case.outputs, "outputs of case '{}'", case.name);
+            assert_eq!(result.blocked, case.blocked, "blocked of case '{}'", case.name);
+        }
+    }
+    
+    #[test]
+    fn capslock_escape_ctrl() {
+        run(vec![
+            Case {
+                name: "tap sends when_alone",
+                config: CAPS_CONFIG,
+                inputs: vec![down(0, "CAPSLOCK"), up(50, "CAPSLOCK")],
+                outputs: &["ESCAPE down", "ESCAPE up"],
+                blocked: &[true, true],
+            },
+            Case {
+                name: "long hold without timeout still sends when_alone",
+                config: CAPS_CONFIG,
+                inputs: vec![down(0, "CAPSLOCK"), down(500, "CAPSLOCK"), up(2000, "CAPSLOCK")],
+                outputs: &["ESCAPE down", "ESCAPE up"],
+                blocked: &[true, true, true],
+            },
+            Case {
+                name: "other key makes it with_other",
+                config: CAPS_CONFIG,
+                inputs: vec![down(0, "CAPSLOCK"), down(50, "A"), up(80, "A"), up(100, "CAPSLOCK")],
+                outputs: &["CTRL down", "CTRL up"],
+                blocked: &[true, false, false, true],
+            },
+            Case {
+                name: "foreign injected input counts as other input",
+                config: CAPS_CONFIG,
+                inputs: vec![down(0, "CAPSLOCK"), input(50, "B", Direction::Down, InputSource::Foreign), up(100, "CAPSLOCK")],
+                outputs: &["CTRL down", "CTRL up"],
+                blocked: &[true, false, true],
+            },
+            Case {
+                name: "unmapped keys pass through untouched",
+                config: CAPS_CONFIG,
+                inputs: vec![down(0, "A"), up(50, "A")],
+                outputs: &[],
+                blocked: &[false, false],
+            },
+        ]);
+    }
+    
+    #[test]
+    fn hold_timeout() {
+        run(vec![
+            Case {
+                name: "release within timeout sends when_alone",
+                config: CAPS_TIMEOUT_CONFIG,
+                inputs: vec![down(0, "CAPSLOCK"), up(150, "CAPSLOCK")],
+                outputs: &["ESCAPE down", "ESCAPE up"],
+                blocked: &[true, true],
+            },
+            Case {
+                name: "release after timeout only releases with_other",
+                config: CAPS_TIMEOUT_CONFIG,
+                inputs: vec![down(0, "CAPSLOCK"), down(500, "CAPSLOCK"), up(2000, "CAPSLOCK")],
+                outputs: &["CTRL down", "CTRL up"],
+                blocked: &[true, true, true],
+            },
+            Case {
+                name: "global default applies to remaps without their own timeout",
+                config: "hold_timeout_ms=100\nremap_key=CAPSLOCK\nwhen_alone=ESCAPE\nwith_other=CTRL\n",
+                inputs: vec![down(0, "CAPSLOCK"), up(150, "CAPSLOCK")],
+                outputs: &["CTRL down", "CTRL up"],
+                blocked: &[true, true],
+            },
+        ]);
+    }
+    
+    const NAV_LAYER_CONFIG: &str = "remap_key=CAPSLOCK\nwhen_alone=ESCAPE\nwith_other=layer:nav\n\
+                                    layer=nav\nmap=H->LEFT\nmap=J->DOWN\n";
+    
+    #[test]
+    fn layers() {
+        run(vec![
+            Case {
+                name: "tapping the layer key sends when_alone",
+                config: NAV_LAYER_CONFIG,
+                inputs: vec![down(0, "CAPSLOCK"), up(50, "CAPSLOCK")],
+                outputs: &["ESCAPE down", "ESCAPE up"],
+                blocked: &[true, true],
+            },
+            Case {
+                name: "mapped keys are routed through the layer",
+                config: NAV_LAYER_CONFIG,
+                inputs: vec![down(0, "CAPSLOCK"), down(50, "H"), up(80, "H"), down(90, "J"), up(95, "J"), up(100, "CAPSLOCK")],
+                outputs: &["LEFT down", "LEFT up", "DOWN down", "DOWN up"],
+                blocked: &[true, true, true, true, true, true],
+            },
+            Case {
+                name: "unmapped keys pass through while the layer is active",
+                config: NAV_LAYER_CONFIG,
+                inputs: vec![down(0, "CAPSLOCK"), down(50, "A"), up(80, "A"), up(100, "CAPSLOCK")],
+                outputs: &[],
+                blocked: &[true, false, false, true],
+            },
+            Case {
+                name: "key pressed on the layer stays mapped after the layer key is released",
+                config: NAV_LAYER_CONFIG,
+                inputs: vec![down(0, "CAPSLOCK"), down(50, "H"), up(80, "CAPSLOCK"), down(90, "H"), up(100, "H")],
+                outputs: &["LEFT down", "LEFT down", "LEFT up"],
+                blocked: &[true, true, true, true, true],
+            },
+            Case {
+                name: "key pressed before the layer became active passes through until released",
+                config: NAV_LAYER_CONFIG,
+                inputs: vec![down(0, "H"), down(20, "CAPSLOCK"), down(50, "H"), up(80, "H"), up(100, "CAPSLOCK")],
+                outputs: &[],
+                blocked: &[false, true, false, false, true],
+            },
+            Case {
+                name: "keys with the same virtual key code are held separately when matching by scan code",
+                config: "match_by=scan_code\nremap_key=CAPSLOCK\nwhen_alone=ESCAPE\nwith_other=layer:nav\n\
+                         layer=nav\nmap=ENTER->LEFT\nmap=NUMPAD_ENTER->RIGHT\n",
+                inputs: vec![
+                    down(0, "CAPSLOCK"), down(20, "ENTER"), down(40, "NUMPAD_ENTER"),
+                    up(60, "ENTER"), up(80, "NUMPAD_ENTER"), up(100, "CAPSLOCK"),
+                ],
+                outputs: &["LEFT down", "RIGHT down", "LEFT up", "RIGHT up"],
+                blocked: &[true, true, true, true, true, true],
+            },
+            Case {
+                name: "layer is inactive once released",
+                config: NAV_LAYER_CONFIG,
+                inputs: vec![down(0, "CAPSLOCK"), down(50, "A"), up(60, "A"), up(80, "CAPSLOCK"), down(90, "H"), up(100, "H")],
+                outputs: &[],
+                blocked: &[true, false, false, true, false, false],
+            },
+        ]);
+    }
+    
+    #[test]
+    fn when_alone_sequences() {
+        run(vec![
+            Case {
+                name: "chord is pressed in order and released in reverse",
+                config: "remap_key=CAPSLOCK\nwhen_alone=CTRL+SHIFT+S\nwith_other=CTRL\n",
+                inputs: vec![down(0, "CAPSLOCK"), up(50, "CAPSLOCK")],
+                outputs: &["CTRL down", "SHIFT down", "S down", "S up", "SHIFT up", "CTRL up"],
+                blocked: &[true, true],
+            },
+            Case {
+                name: "sequence and text are sent in order",
+                config: "remap_key=CAPSLOCK\nwhen_alone=ESCAPE, CTRL+S, \":w\"\nwith_other=CTRL\n",
+                inputs: vec![down(0, "CAPSLOCK"), up(50, "CAPSLOCK")],
+                outputs: &["ESCAPE down", "ESCAPE up", "CTRL down", "S down", "S up", "CTRL up", "text :w"],
+                blocked: &[true, true],
+            },
+            Case {
+                name: "with_other is unaffected",
+                config: "remap_key=CAPSLOCK\nwhen_alone=ESCAPE, CTRL+S\nwith_other=CTRL\n",
+                inputs: vec![down(0, "CAPSLOCK"), down(50, "A"), up(60, "A"), up(100, "CAPSLOCK")],
+                outputs: &["CTRL down", "CTRL up"],
+                blocked: &[true, false, false, true],
+            },
+        ]);
+    }
+    
+    #[test]
+    fn with_other_chords() {
+        let hyper = "remap_key=CAPSLOCK\nwhen_alone=ESCAPE\nwith_other=CTRL+ALT+SHIFT+LWIN\nhold_timeout_ms=200\n";
+        run(vec![
+            Case {
+                name: "modifiers are pressed in order and released in reverse",
+                config: hyper,
+                inputs: vec![down(0, "CAPSLOCK"), down(50, "T"), up(60, "T"), up(100, "CAPSLOCK")],
+                outputs: &["CTRL down", "ALT down", "SHIFT down", "LWIN down", "LWIN up", "SHIFT up", "ALT up", "CTRL up"],
+                blocked: &[true, false, false, true],
+            },
+            Case {
+                name: "hold timeout presses the whole chord",
+                config: hyper,
+                inputs: vec![down(0, "CAPSLOCK"), up(300, "CAPSLOCK")],
+                outputs: &["CTRL down", "ALT down", "SHIFT down", "LWIN down", "LWIN up", "SHIFT up", "ALT up", "CTRL up"],
+                blocked: &[true, true],
+            },
+        ]);
+    }
+    
+    const PERMISSIVE_CONFIG: &str = "remap_key=CAPSLOCK\nwhen_alone=ESCAPE\nwith_other=CTRL\nhold_mode=permissive_hold\n";
+    
+    #[test]
+    fn permissive_hold() {
+        run(vec![
+            Case {
+                name: "rolled tap stays a tap and replays the next key after when_alone",
+                config: PERMISSIVE_CONFIG,
+                inputs: vec![down(0, "CAPSLOCK"), down(30, "A"), up(50, "CAPSLOCK"), up(80, "A")],
+                outputs: &["ESCAPE down", "ESCAPE up", "A down"],
+                blocked: &[true, true, true, false],
+            },
+            Case {
+                name: "press and release while held commits to with_other",
+                config: PERMISSIVE_CONFIG,
+                inputs: vec![down(0, "CAPSLOCK"), down(30, "A"), up(50, "A"), up(80, "CAPSLOCK")],
+                outputs: &["CTRL down", "A down", "A up", "CTRL up"],
+                blocked: &[true, true, true, true],
+            },
+            Case {
+                name: "later keys stay buffered behind the decision",
+                config: PERMISSIVE_CONFIG,
+                inputs: vec![down(0, "CAPSLOCK"), down(30, "A"), down(40, "B"), up(50, "B"), up(60, "A"), up(80, "CAPSLOCK")],
+                outputs: &["CTRL down", "A down", "B down", "B up", "CTRL up"],
+                blocked: &[true, true, true, true, false, true],
+            },
+            Case {
+                name: "release of a key pressed before does not decide",
+                config: PERMISSIVE_CONFIG,
+                inputs: vec![down(0, "A"), down(30, "CAPSLOCK"), up(50, "A"), up(80, "CAPSLOCK")],
+                outputs: &["ESCAPE down", "ESCAPE up"],
+                blocked: &[false, true, false, true],
+            },
+            Case {
+                name: "release of a key with the same virtual key code does not decide when matching by scan code",
+                config: "match_by=scan_code\nremap_key=CAPSLOCK\nwhen_alone=ESCAPE\nwith_other=CTRL\nhold_mode=permissive_hold\n",
+                inputs: vec![
+                    down(0, "ENTER"), down(30, "CAPSLOCK"), down(40, "NUMPAD_ENTER"),
+                    up(50, "ENTER"), up(80, "CAPSLOCK"), up(100, "NUMPAD_ENTER"),
+                ],
+                outputs: &["ESCAPE down", "ESCAPE up", "NUMPAD_ENTER down", "ENTER up"],
+                blocked: &[false, true, true, true, true, false],
+            },
+            Case {
+                name: "hold timeout decides and replays",
+                config: "remap_key=CAPSLOCK\nwhen_alone=ESCAPE\nwith_other=CTRL\nhold_mode=permissive_hold\nhold_timeout_ms=200\n",
+                inputs: vec![down(0, "CAPSLOCK"), down(50, "A"), up(300, "A"), up(400, "CAPSLOCK")],
+                outputs: &["CTRL down", "A down", "CTRL up"],
+                blocked: &[true, true, false, true],
+            },
+            Case {
+                name: "mouse click decides immediately",
+                config: PERMISSIVE_CONFIG,
+                inputs: vec![down(0, "CAPSLOCK"), (50, MOUSE_DUMMY_VK, 0, Direction::Down, InputSource::Physical), up(80, "CAPSLOCK")],
+                outputs: &["CTRL down", "CTRL up"],
+                blocked: &[true, false, true],
+            },
+            Case {
+                name: "replayed keys are routed through the layer",
+                config: "remap_key=CAPSLOCK\nwhen_alone=ESCAPE\nwith_other=layer:nav\nhold_mode=permissive_hold\nlayer=nav\nmap=H->LEFT\n",
+                inputs: vec![down(0, "CAPSLOCK"), down(30, "H"), up(50, "H"), up(80, "CAPSLOCK")],
+                outputs: &["LEFT down", "LEFT up"],
+                blocked: &[true, true, true, true],
+            },
+        ]);
+    }
+    
+    const BALANCED_CONFIG: &str = "remap_key=CAPSLOCK\nwhen_alone=ESCAPE\nwith_other=CTRL\nhold_mode=balanced\nhold_timeout_ms=200\n";
+    
+    #[test]
+    fn balanced() {
+        run(vec![
+            Case {
+                name: "release within the tapping term replays the queue after when_alone",
+                config: BALANCED_CONFIG,
+                inputs: vec![down(0, "CAPSLOCK"), down(30, "A"), up(50, "A"), up(80, "CAPSLOCK")],
+                outputs: &["ESCAPE down", "ESCAPE up", "A down", "A up"],
+                blocked: &[true, true, true, true],
+            },
+            Case {
+                name: "tapping term expiry replays the queue after with_other",
+                config: BALANCED_CONFIG,
+                inputs: vec![down(0, "CAPSLOCK"), down(30, "A"), up(50, "A"), up(250, "CAPSLOCK")],
+                outputs: &["CTRL down", "A down", "A up", "CTRL up"],
+                blocked: &[true, true, true, true],
+            },
+            Case {
+                name: "keys after the decision are not delayed",
+                config: BALANCED_CONFIG,
+                inputs: vec![down(0, "CAPSLOCK"), down(30, "A"), down(250, "B"), up(260, "B"), up(270, "A"), up(300, "CAPSLOCK")],
+                outputs: &["CTRL down", "A down", "CTRL up"],
+                blocked: &[true, true, false, false, false, true],
+            },
+            Case {
+                name: "tapping term defaults to 200ms",
+                config: "remap_key=CAPSLOCK\nwhen_alone=ESCAPE\nwith_other=CTRL\nhold_mode=balanced\n",
+                inputs: vec![down(0, "CAPSLOCK"), down(30, "A"), up(190, "CAPSLOCK"), up(195, "A")],
+                outputs: &["ESCAPE down", "ESCAPE up", "A down"],
+                blocked: &[true, true, true, false],
+            },
+        ]);
+    }
+    
+    const QUICK_TAP_CONFIG: &str = "remap_key=CAPSLOCK\nwhen_alone=ESCAPE\nwith_other=CTRL\nquick_tap_ms=150\n";
+    
+    #[test]
+    fn quick_tap() {
+        run(vec![
+            Case {
+                name: "tap then hold holds when_alone and repeats it",
+                config: QUICK_TAP_CONFIG,
+                inputs: vec![down(0, "CAPSLOCK"), up(30, "CAPSLOCK"), down(100, "CAPSLOCK"), down(600, "CAPSLOCK"), up(650, "CAPSLOCK")],
+                outputs: &["ESCAPE down", "ESCAPE up", "ESCAPE down", "ESCAPE down", "ESCAPE up"],
+                blocked: &[true, true, true, true, true],
+            },
+            Case {
+                name: "other keys while quick-tap held are not modified",
+                config: QUICK_TAP_CONFIG,
+                inputs: vec![down(0, "CAPSLOCK"), up(30, "CAPSLOCK"), down(100, "CAPSLOCK"), down(200, "A"), up(210, "A"), up(300, "CAPSLOCK")],
+                outputs: &["ESCAPE down", "ESCAPE up", "ESCAPE down", "ESCAPE up"],
+                blocked: &[true, true, true, false, false, true],
+            },
+            Case {
+                name: "second press after the window is dual-role again",
+                config: QUICK_TAP_CONFIG,
+                inputs: vec![down(0, "CAPSLOCK"), up(30, "CAPSLOCK"), down(200, "CAPSLOCK"), down(250, "A"), up(260, "A"), up(300, "CAPSLOCK")],
+                outputs: &["ESCAPE down", "ESCAPE up", "CTRL down", "CTRL up"],
+                blocked: &[true, true, true, false, false, true],
+            },
+            Case {
+                name: "hold after with_other is not a quick tap",
+                config: QUICK_TAP_CONFIG,
+                inputs: vec![down(0, "CAPSLOCK"), down(10, "A"), up(20, "A"), up(30, "CAPSLOCK"), down(60, "CAPSLOCK"), up(90, "CAPSLOCK")],
+                outputs: &["CTRL down", "CTRL up", "ESCAPE down", "ESCAPE up"],
+                blocked: &[true, false, false, true, true, true],
+            },
+            Case {
+                name: "only the last stroke of a sequence is held",
+                config: "remap_key=CAPSLOCK\nwhen_alone=ESCAPE, CTRL+Z\nwith_other=CTRL\nquick_tap_ms=150\n",
+                inputs: vec![down(0, "CAPSLOCK"), up(30, "CAPSLOCK"), down(100, "CAPSLOCK"), up(200, "CAPSLOCK")],
+                outputs: &[
+                    "ESCAPE down", "ESCAPE up", "CTRL down", "Z down", "Z up", "CTRL up",
+                    "ESCAPE down", "ESCAPE up", "CTRL down", "Z down", "Z up", "CTRL up",
+                ],
+                blocked: &[true, true, true, true],
+            },
+        ]);
+    }
+    
+    const RETRO_TAP_CONFIG: &str = "remap_key=SPACE\nwhen_alone=SPACE\nwith_other=LSHIFT\nhold_timeout_ms=200\nretro_tap=true\n";
+    
+    #[test]
+    fn retro_tap() {
+        run(vec![
+            Case {
+                name: "long solitary hold sends when_alone after with_other",
+                config: RETRO_TAP_CONFIG,
+                inputs: vec![down(0, "SPACE"), up(500, "SPACE")],
+                outputs: &["LSHIFT down", "LSHIFT up", "SPACE down", "SPACE up"],
+                blocked: &[true, true],
+            },
+            Case {
+                name: "other key during the hold suppresses when_alone",
+                config: RETRO_TAP_CONFIG,
+                inputs: vec![down(0, "SPACE"), down(300, "A"), up(310, "A"), up(500, "SPACE")],
+                outputs: &["LSHIFT down", "LSHIFT up"],
+                blocked: &[true, false, false, true],
+            },
+            Case {
+                name: "mouse click during the hold suppresses when_alone",
+                config: RETRO_TAP_CONFIG,
+                inputs: vec![down(0, "SPACE"), (300, MOUSE_DUMMY_VK, 0, Direction::Down, InputSource::Physical), up(500, "SPACE")],
+                outputs: &["LSHIFT down", "LSHIFT up"],
+                blocked: &[true, false, true],
+            },
+            Case {
+                name: "without retro_tap a long hold only sends with_other",
+                config: "remap_key=SPACE\nwhen_alone=SPACE\nwith_other=LSHIFT\nhold_timeout_ms=200\n",
+                inputs: vec![down(0, "SPACE"), up(500, "SPACE")],
+                outputs: &["LSHIFT down", "LSHIFT up"],
+                blocked: &[true, true],
+            },
+            Case {
+                name: "injected input does not count as another key",
+                config: RETRO_TAP_CONFIG,
+                inputs: vec![down(0, "SPACE"), input(250, "LSHIFT", Direction::Down, InputSource::Own), up(500, "SPACE")],
+                outputs: &["LSHIFT down", "LSHIFT up", "SPACE down", "SPACE up"],
+                blocked: &[true, false, true],
+            },
+        ]);
+    }
+    
+    #[test]
+    fn generic_modifiers_match_both_sides() {
+        run(vec![
+            Case {
+                name: "generic CTRL matches LCTRL",
+                config: "remap_key=CTRL\nwhen_alone=ESCAPE\nwith_other=CTRL\n",
+                inputs: vec![down(0, "LCTRL"), up(50, "LCTRL")],
+                outputs: &["ESCAPE down", "ESCAPE up"],
+                blocked: &[true, true],
+            },
+            Case {
+                name: "generic CTRL matches RCTRL",
+                config: "remap_key=CTRL\nwhen_alone=ESCAPE\nwith_other=CTRL\n",
+                inputs: vec![down(0, "RCTRL"), down(10, "C"), up(20, "C"), up(50, "RCTRL")],
+                outputs: &["CTRL down", "CTRL up"],
+                blocked: &[true, false, false, true],
+            },
+            Case {
+                name: "sided LSHIFT does not match RSHIFT",
+                config: "remap_key=LSHIFT\nwhen_alone=\"(\"\nwith_other=LSHIFT\n",
+                inputs: vec![down(0, "RSHIFT"), up(50, "RSHIFT"), down(100, "LSHIFT"), up(150, "LSHIFT")],
+                outputs: &["text ("],
+                blocked: &[false, false, true, true],
+            },
+            Case {
+                name: "generic modifiers in layers match both sides",
+                config: "remap_key=CAPSLOCK\nwhen_alone=ESCAPE\nwith_other=layer:nav\nlayer=nav\nmap=ALT->HOME\n",
+                inputs: vec![down(0, "CAPSLOCK"), down(10, "RALT"), up(20, "RALT"), up(50, "CAPSLOCK")],
+                outputs: &["HOME down", "HOME up"],
+                blocked: &[true, true, true, true],
+            },
+        ]);
+    }
+    
+    #[test]
+    fn nested_dual_role_keys() {
+        const HOLD: &str = "remap_key=CAPSLOCK\nwhen_alone=ESCAPE\nwith_other=CTRL\n\
+                            remap_key=SPACE\nwhen_alone=SPACE\nwith_other=LSHIFT\n";
+        const PERMISSIVE: &str = "hold_mode=permissive_hold\n\
+                                  remap_key=CAPSLOCK\nwhen_alone=ESCAPE\nwith_other=CTRL\n\
+                                  remap_key=SPACE\nwhen_alone=SPACE\nwith_other=LSHIFT\n";
+        const BALANCED: &str = "hold_mode=balanced\n\
+                                remap_key=CAPSLOCK\nwhen_alone=ESCAPE\nwith_other=CTRL\n\
+                                remap_key=SPACE\nwhen_alone=SPACE\nwith_other=LSHIFT\n";
+        const MIXED: &str = "remap_key=CAPSLOCK\nwhen_alone=ESCAPE\nwith_other=CTRL\nhold_mode=permissive_hold\n\
+                             remap_key=SPACE\nwhen_alone=SPACE\nwith_other=LSHIFT\n";
+        
+        run(vec![
+            Case {
+                name: "hold_on_other_press: stacked modifiers",
+                config: HOLD,
+                inputs: vec![
+                    down(0, "CAPSLOCK"), down(20, "SPACE"), down(40, "X"), up(60, "X"),
+                    up(80, "SPACE"), up(100, "CAPSLOCK"),
+                ],
+                outputs: &["CTRL down", "LSHIFT down", "LSHIFT up", "CTRL up"],
+                blocked: &[true, true, false, false, true, true],
+            },
+            Case {
+                name: "hold_on_other_press: inner key tapped",
+                config: HOLD,
+                inputs: vec![down(0, "CAPSLOCK"), down(20, "SPACE"), up(40, "SPACE"), up(60, "CAPSLOCK")],
+                outputs: &["CTRL down", "SPACE down", "SPACE up", "CTRL up"],
+                blocked: &[true, true, true, true],
+            },
+            Case {
+                name: "hold_on_other_press: outer key released first",
+                config: HOLD,
+                inputs: vec![down(0, "CAPSLOCK"), down(20, "SPACE"), up(40, "CAPSLOCK"), up(60, "SPACE")],
+                outputs: &["CTRL down", "CTRL up", "SPACE down", "SPACE up"],
+                blocked: &[true, true, true, true],
+            },
+            Case {
+                name: "permissive_hold: stacked modifiers",
+                config: PERMISSIVE,
+                inputs: vec![
+                    down(0, "CAPSLOCK"), down(20, "SPACE"), down(40, "X"), up(60, "X"),
+                    up(80, "SPACE"), up(100, "CAPSLOCK"),
+                ],
+                outputs: &["CTRL down", "LSHIFT down", "X down", "X up", "LSHIFT up", "CTRL up"],
+                blocked: &[true, true, true, true, true, true],
+            },
+            Case {
+                name: "permissive_hold: inner key tapped",
+                config: PERMISSIVE,
+                inputs: vec![down(0, "CAPSLOCK"), down(20, "SPACE"), up(40, "SPACE"), up(60, "CAPSLOCK")],
+                outputs: &["CTRL down", "SPACE down", "SPACE up", "CTRL up"],
+                blocked: &[true, true, true, true],
+            },
+            Case {
+                name: "permissive_hold: rolled keys stay taps",
+                config: PERMISSIVE,
+                inputs: vec![down(0, "CAPSLOCK"), down(20, "SPACE"), up(40, "CAPSLOCK"), up(60, "SPACE")],
+                outputs: &["ESCAPE down", "ESCAPE up", "SPACE down", "SPACE up"],
+                blocked: &[true, true, true, true],
+            },
+            Case {
+                name: "balanced: inner key tapped within the tapping term",
+                config: BALANCED,
+                inputs: vec![down(0, "CAPSLOCK"), down(20, "SPACE"), up(40, "SPACE"), up(60, "CAPSLOCK")],
+                outputs: &["ESCAPE down", "ESCAPE up", "SPACE down", "SPACE up"],
+                blocked: &[true, true, true, true],
+            },
+            Case {
+                name: "balanced: inner key tapped while outer key is held past the tapping term",
+                config: BALANCED,
+                inputs: vec![down(0, "CAPSLOCK"), down(20, "SPACE"), up(40, "SPACE"), up(300, "CAPSLOCK")],
+                outputs: &["CTRL down", "SPACE down", "SPACE up", "CTRL up"],
+                blocked: &[true, true, true, true],
+            },
+            Case {
+                name: "balanced: both keys held past the tapping term",
+                config: BALANCED,
+                inputs: vec![down(0, "CAPSLOCK"), down(20, "SPACE"), up(500, "SPACE"), up(520, "CAPSLOCK")],
+                outputs: &["CTRL down", "LSHIFT down", "LSHIFT up", "CTRL up"],
+                blocked: &[true, true, true, true],
+            },
+            Case {
+                name: "permissive_hold outer key with hold_on_other_press inner key",
+                config: MIXED,
+                inputs: vec![
+                    down(0, "CAPSLOCK"), down(20, "SPACE"), down(40, "X"), up(60, "X"),
+                    up(80, "SPACE"), up(100, "CAPSLOCK"),
+                ],
+                outputs: &["CTRL down", "LSHIFT down", "X down", "X up", "LSHIFT up", "CTRL up"],
+                blocked: &[true, true, true, true, true, true],
+            },
+        ]);
+    }
+    
+    #[test]
+    fn shared_output_keys() {
+        const TWO_CTRLS: &str = "remap_key=CAPSLOCK\nwhen_alone=ESCAPE\nwith_other=CTRL\n\
+                                 remap_key=ENTER\nwhen_alone=ENTER\nwith_other=CTRL\n";
+        
+        run(vec![
+            Case {
+                name: "released after the last remap lets go",
+                config: TWO_CTRLS,
+                inputs: vec![
+                    down(0, "CAPSLOCK"), down(20, "ENTER"), down(40, "C"), up(60, "C"),
+                    up(80, "CAPSLOCK"), down(100, "V"), up(120, "V"), up(140, "ENTER"),
+                ],
+                outputs: &["CTRL down", "CTRL up"],
+                blocked: &[true, true, false, false, true, false, false, true],
+            },
+            Case {
+                name: "chords share only their common keys",
+                config: "remap_key=CAPSLOCK\nwhen_alone=ESCAPE\nwith_other=CTRL+SHIFT\n\
+                         remap_key=ENTER\nwhen_alone=ENTER\nwith_other=CTRL\n",
+                inputs: vec![down(0, "CAPSLOCK"), down(20, "ENTER"), down(40, "C"), up(60, "C"), up(80, "CAPSLOCK"), up(100, "ENTER")],
+                outputs: &["CTRL down", "SHIFT down", "SHIFT up", "CTRL up"],
+                blocked: &[true, true, false, false, true, true],
+            },
+            Case {
+                name: "generic and sided modifiers share the left key",
+                config: "remap_key=CAPSLOCK\nwhen_alone=ESCAPE\nwith_other=CTRL\n\
+                         remap_key=ENTER\nwhen_alone=ENTER\nwith_other=LCTRL\n\
+                         remap_key=TAB\nwhen_alone=TAB\nwith_other=RCTRL\n",
+                inputs: vec![
+                    down(0, "CAPSLOCK"), down(20, "ENTER"), down(30, "TAB"), down(40, "C"), up(60, "C"),
+                    up(80, "CAPSLOCK"), up(100, "TAB"), up(120, "ENTER"),
+                ],
+                outputs: &["CTRL down", "RCTRL down", "RCTRL up", "CTRL up"],
+                blocked: &[true, true, true, false, false, true, true, true],
+            },
+            Case {
+                name: "layer keys and remaps share output keys",
+                config: "remap_key=CAPSLOCK\nwhen_alone=ESCAPE\nwith_other=layer:nav\n\
+                         remap_key=TAB\nwhen_alone=TAB\nwith_other=LCTRL\n\
+                         layer=nav\nmap=H->LCTRL\n",
+                inputs: vec![
+                    down(0, "CAPSLOCK"), down(20, "H"), down(40, "TAB"), down(60, "X"), up(80, "X"),
+                    up(100, "H"), up(120, "TAB"), up(140, "CAPSLOCK"),
+                ],
+                outputs: &["LCTRL down", "LCTRL up"],
+                blocked: &[true, true, true, false, false, true, true, true],
+            },
+        ]);
+        
+        // 第一个 remap 释放时 CTRL 仍然按住
+        let mut manager = RemapManager::new(parse_config(TWO_CTRLS).unwrap(), RecordingSink::default());
+        let ctrl = find_key_by_name("CTRL").unwrap();
+        manager.handle_input(vk("CAPSLOCK"), 0x3A, Direction::Down, InputSource::Physical, 0);
+        manager.handle_input(vk("ENTER"), 0x1C, Direction::Down, InputSource::Physical, 20);
+        manager.handle_input(vk("C"), 0x2E, Direction::Down, InputSource::Physical, 30);
+        manager.handle_input(vk("CAPSLOCK"), 0x3A, Direction::Up, InputSource::Physical, 40);
+        assert_eq!(manager.output_mut().events, [RecordedEvent::Key(ctrl, Direction::Down)]);
+        
+        manager.handle_input(vk("C"), 0x2E, Direction::Up, InputSource::Physical, 50);
+        manager.handle_input(vk("ENTER"), 0x1C, Direction::Up, InputSource::Physical, 60);
+        assert_eq!(manager.output_mut().events, [
+            RecordedEvent::Key(ctrl, Direction::Down),
+            RecordedEvent::Key(ctrl, Direction::Up),
+        ]);
+        assert!(manager.is_idle());
+    }
+    
+    #[test]
+    fn injected_input_sources() {
+        let own = |time, name| input(time, name, Direction::Down, InputSource::Own);
+        let foreign_down = |time, name| input(time, name, Direction::Down, InputSource::Foreign);
+        let foreign_up = |time, name| input(time, name, Direction::Up, InputSource::Foreign);
+        
+        run(vec![
+            Case {
+                name: "own injected input is ignored",
+                config: CAPS_CONFIG,
+                inputs: vec![down(0, "CAPSLOCK"), own(50, "LCTRL"), own(60, "CAPSLOCK"), up(100, "CAPSLOCK")],
+                outputs: &["ESCAPE down", "ESCAPE up"],
+                blocked: &[true, false, false, true],
+            },
+            Case {
+                name: "foreign injected input can be ignored",
+                config: "foreign_injected=ignore\nremap_key=CAPSLOCK\nwhen_alone=ESCAPE\nwith_other=CTRL\n",
+                inputs: vec![down(0, "CAPSLOCK"), foreign_down(50, "B"), foreign_down(60, "CAPSLOCK"), up(100, "CAPSLOCK")],
+                outputs: &["ESCAPE down", "ESCAPE up"],
+                blocked: &[true, false, false, true],
+            },
+            Case {
+                name: "foreign injected input is buffered in order with physical keys",
+                config: "hold_mode=permissive_hold\nremap_key=CAPSLOCK\nwhen_alone=ESCAPE\nwith_other=CTRL\n",
+                inputs: vec![down(0, "CAPSLOCK"), down(20, "A"), foreign_down(40, "B"), up(60, "CAPSLOCK")],
+                outputs: &["ESCAPE down", "ESCAPE up", "A down", "B down"],
+                blocked: &[true, true, true, true],
+            },
+            Case {
+                name: "foreign injected input cancels retro_tap",
+                config: "remap_key=SPACE\nwhen_alone=SPACE\nwith_other=LSHIFT\nhold_timeout_ms=200\nretro_tap=true\n",
+                inputs: vec![down(0, "SPACE"), foreign_down(250, "B"), foreign_up(300, "B"), up(500, "SPACE")],
+                outputs: &["LSHIFT down", "LSHIFT up"],
+                blocked: &[true, false, false, true],
+            },
+            Case {
+                name: "foreign injected input is not mapped by layers",
+                config: "remap_key=CAPSLOCK\nwhen_alone=ESCAPE\nwith_other=layer:nav\nlayer=nav\nmap=H->LEFT\n",
+                inputs: vec![down(0, "CAPSLOCK"), foreign_down(20, "H"), foreign_up(40, "H"), up(60, "CAPSLOCK")],
+                outputs: &[],
+                blocked: &[true, false, false, true],
+            },
+            Case {
+                name: "foreign injected remap key is not remapped by default",
+                config: CAPS_CONFIG,
+                inputs: vec![foreign_down(0, "CAPSLOCK"), foreign_up(50, "CAPSLOCK")],
+                outputs: &[],
+                blocked: &[false, false],
+            },
+            Case {
+                name: "foreign injected input can be remapped like physical keys",
+                config: "foreign_injected=remap\nremap_key=CAPSLOCK\nwhen_alone=ESCAPE\nwith_other=CTRL\n",
+                inputs: vec![foreign_down(0, "CAPSLOCK"), foreign_up(50, "CAPSLOCK")],
+                outputs: &["ESCAPE down", "ESCAPE up"],
+                blocked: &[true, true],
+            },
+            Case {
+                name: "remapped foreign injected input is buffered while deciding",
+                config: "foreign_injected=remap\nhold_mode=permissive_hold\nremap_key=CAPSLOCK\nwhen_alone=ESCAPE\nwith_other=CTRL\n",
+                inputs: vec![down(0, "CAPSLOCK"), foreign_down(50, "B"), up(100, "CAPSLOCK")],
+                outputs: &["ESCAPE down", "ESCAPE up", "B down"],
+                blocked: &[true, true, true],
+            },
+        ]);
+    }
+    
+    #[test]
+    fn match_by_scan_code() {
+        let config = parse_config(
+            "match_by=scan_code\nremap_key=NUMPAD_ENTER\nwhen_alone=TAB\nwith_other=LALT\n\
+             remap_key=CTRL\nwhen_alone=ESCAPE\nwith_other=CTRL\n",
+        ).unwrap();
+        let mut manager = RemapManager::new(config, RecordingSink::default());
+        
+        // 回车和小键盘回车虚拟键码相同，只有扫描码不同
+        assert!(!manager.handle_input(vk("ENTER"), 0x1C, Direction::Down, InputSource::Physical, 0));
+        assert!(!manager.handle_input(vk("ENTER"), 0x1C, Direction::Up, InputSource::Physical, 10));
+        assert!(manager.handle_input(vk("ENTER"), 0xE01C, Direction::Down, InputSource::Physical, 20));
+        assert!(manager.handle_input(vk("ENTER"), 0xE01C, Direction::Up, InputSource::Physical, 30));
+        
+        // 布局改变了虚拟键码时仍按物理按键匹配，通用修饰键仍匹配两侧
+        assert!(manager.handle_input(0, 0xE01C, Direction::Down, InputSource::Physical, 40));
+        assert!(manager.handle_input(0, 0xE01C, Direction::Up, InputSource::Physical, 50));
+        assert!(manager.handle_input(vk("RCTRL"), 0xE01D, Direction::Down, InputSource::Physical, 60));
+        assert!(manager.handle_input(vk("RCTRL"), 0xE01D, Direction::Up, InputSource::Physical, 70));
+        
+        let tab = find_key_by_name("TAB").unwrap();
+        let escape = find_key_by_name("ESCAPE").unwrap();
+        assert_eq!(manager.output_mut().events, vec![
+            RecordedEvent::Key(tab, Direction::Down),
+            RecordedEvent::Key(tab, Direction::Up),
+            RecordedEvent::Key(tab, Direction::Down),
+            RecordedEvent::Key(tab, Direction::Up),
+            RecordedEvent::Key(escape, Direction::Down),
+            RecordedEvent::Key(escape, Direction::Up),
+        ]);
+    }
+    
+    #[test]
+    fn reload_replaces_remaps() {
+        let mut manager = RemapManager::new(parse_config(CAPS_CONFIG).unwrap(), RecordingSink::default());
+        assert!(manager.is_idle());
+        
+        manager.handle_input(vk("CAPSLOCK"), 0x3A, Direction::Down, InputSource::Physical, 0);
+        assert!(!manager.is_idle());
+        manager.handle_input(vk("CAPSLOCK"), 0x3A, Direction::Up, InputSource::Physical, 50);
+        assert!(manager.is_idle());
+        
+        manager.reload(parse_config("remap_key=TAB\nwhen_alone=TAB\nwith_other=LALT\n").unwrap());
+        manager.output_mut().events.clear();
+        assert!(!manager.handle_input(vk("CAPSLOCK"), 0x3A, Direction::Down, InputSource::Physical, 100));
+        assert!(manager.handle_input(vk("TAB"), 0x0F, Direction::Down, InputSource::Physical, 110));
+        assert!(manager.handle_input(vk("TAB"), 0x0F, Direction::Up, InputSource::Physical, 120));
+        assert_eq!(manager.output_mut().events, vec![
+            RecordedEvent::Key(find_key_by_name("TAB").unwrap(), Direction::Down),
+            RecordedEvent::Key(find_key_by_name("TAB").unwrap(), Direction::Up),
+        ]);
+    }
+    
+    #[test]
+    fn tick_replays_queued_keys() {
+        let config = parse_config(BALANCED_CONFIG).unwrap();
+        let mut manager = RemapManager::new(config, RecordingSink::default());
+        
+        manager.handle_input(vk("CAPSLOCK"), 0x3A, Direction::Down, InputSource::Physical, 0);
+        assert!(manager.handle_input(vk("A"), 0x1E, Direction::Down, InputSource::Physical, 30));
+        manager.tick(150);
+        assert!(manager.output_mut().events.is_empty());
+        
+        manager.tick(210);
+        assert_eq!(manager.output_mut().events, vec![
+            RecordedEvent::Key(find_key_by_name("CTRL").unwrap(), Direction::Down),
+            RecordedEvent::Key(find_key_by_name("A").unwrap(), Direction::Down),
+        ]);
+    }
+    
+    #[test]
+    fn tick_commits_to_with_other_without_input() {
+        let config = parse_config(CAPS_TIMEOUT_CONFIG).unwrap();
+        let mut manager = RemapManager::new(config, RecordingSink::default());
+        
+        manager.handle_input(vk("CAPSLOCK"), 0x3A, Direction::Down, InputSource::Physical, 0);
+        manager.tick(100);
+        assert!(manager.output_mut().events.is_empty());
+        
+        manager.tick(250);
+        assert_eq!(manager.output_mut().events, vec![
+            RecordedEvent::Key(find_key_by_name("CTRL").unwrap(), Direction::Down),
+        ]);
+    }
+}
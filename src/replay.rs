@@ -0,0 +1,63 @@
+// 测试辅助：按脚本回放输入事件，收集 RemapManager 的输出和拦截决定
+use crate::config::parse_config;
+use crate::input::{Direction, InputSource, RecordedEvent, RecordingSink};
+use crate::keys::find_key_by_name;
+use crate::remap::RemapManager;
+
+// (时间戳, 虚拟键码, 扫描码, 方向, 输入来源)
+pub type ScriptedInput = (u64, u32, u32, Direction, InputSource);
+
+#[derive(Debug)]
+pub struct ReplayResult {
+    pub outputs: Vec<RecordedEvent>,
+    pub blocked: Vec<bool>,
+}
+
+impl ReplayResult {
+    // 以 "ESCAPE down"、"text hello" 的形式列出输出，便于表格断言
+    pub fn output_names(&self) -> Vec<String> {
+        self.outputs.iter()
+            .map(|event| match event {
+                RecordedEvent::Key(key_def, Direction::Down) => format!("{} down", key_def.name),
+                RecordedEvent::Key(key_def, Direction::Up) => format!("{} up", key_def.name),
+                RecordedEvent::Text(text) => format!("text {}", text),
+            })
+            .collect()
+    }
+}
+
+pub fn vk(name: &str) -> u32 {
+    find_key_by_name(name)
+        .unwrap_or_else(|| panic!("unknown key name '{}'", name))
+        .virt_code
+}
+
+// 按键名给出两种键码，如 ENTER 和 NUMPAD_ENTER 的扫描码不同
+pub fn input(time: u64, name: &str, direction: Direction, source: InputSource) -> ScriptedInput {
+    let key_def = find_key_by_name(name).unwrap_or_else(|| panic!("unknown key name '{}'", name));
+    (time, key_def.virt_code, key_def.scan_code, direction, source)
+}
+
+pub fn down(time: u64, name: &str) -> ScriptedInput {
+    input(time, name, Direction::Down, InputSource::Physical)
+}
+
+pub fn up(time: u64, name: &str) -> ScriptedInput {
+    input(time, name, Direction::Up, InputSource::Physical)
+}
+
+pub fn replay(config: &str, inputs: &[ScriptedInput]) -> ReplayResult {
+    let config = parse_config(config).unwrap_or_else(|e| panic!("invalid test config: {}", e));
+    let mut manager = RemapManager::new(config, RecordingSink::default());
+    
+    let blocked = inputs.iter()
+        .map(|&(time, virt_code, scan_code, direction, source)| {
+            manager.handle_input(virt_code, scan_code, direction, source, time)
+        })
+        .collect();
+    
+    ReplayResult {
+        outputs: std::mem::take(&mut manager.output_mut().events),
+        blocked,
+    }
+}
@@ -0,0 +1,116 @@
+use crate::config::{load_config, Config};
+use crate::input::OutputSink;
+use crate::remap::RemapManager;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+// 两次检查配置文件修改时间的最小间隔（毫秒）
+const POLL_INTERVAL_MS: u64 = 500;
+
+// 轮询配置文件的修改时间，变化后重新加载并在所有键释放后替换配置
+pub struct ConfigWatcher {
+    path: PathBuf,
+    modified: Option<SystemTime>,
+    checked_at: u64,
+    // 已加载但还在等待按键全部释放的新配置
+    staged: Option<Config>,
+}
+
+impl ConfigWatcher {
+    pub fn new(path: PathBuf) -> Self {
+        let modified = modified_time(&path);
+        
+        Self {
+            path,
+            modified,
+            checked_at: 0,
+            staged: None,
+        }
+    }
+    
+    // 由主循环定时调用；time 与 RemapManager 使用相同的毫秒时间戳
+    pub fn poll<S: OutputSink>(&mut self, manager: &mut RemapManager<S>, time: u64) {
+        if time.saturating_sub(self.checked_at) >= POLL_INTERVAL_MS {
+            self.checked_at = time;
+            self.check_file();
+        }
+        
+        if self.staged.is_some() && manager.is_idle() {
+            if let Some(config) = self.staged.take() {
+                manager.reload(config);
+                println!("Configuration reloaded from {}", self.path.display());
+            }
+        }
+    }
+    
+    fn check_file(&mut self) {
+        let modified = modified_time(&self.path);
+        if modified.is_none() || modified == self.modified {
+            return;
+        }
+        self.modified = modified;
+        
+        // 解析失败时保留当前配置，等待下一次修改
+        match load_config(&self.path) {
+            Ok(config) => {
+                for warning in &config.warnings {
+                    println!("{}", warning.warning());
+                }
+                self.staged = Some(config);
+            }
+            Err(e) => println!("Error reloading config, keeping the current one: {}", e),
+        }
+    }
+}
+
+fn modified_time(path: &Path) -> Option<SystemTime> {
+    fs::metadata(path).and_then(|metadata| metadata.modified()).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::parse_config;
+    use crate::input::{Direction, InputSource, RecordingSink};
+    use crate::replay::vk;
+    use std::fs::File;
+    use std::time::Duration;
+    
+    const CAPS_CONFIG: &str = "remap_key=CAPSLOCK\nwhen_alone=ESCAPE\nwith_other=CTRL\n";
+    const TAB_CONFIG: &str = "remap_key=TAB\nwhen_alone=TAB\nwith_other=LALT\n";
+    
+    // 文件系统的时间精度可能很粗，直接把修改时间往后调
+    fn write_config(path: &Path, content: &str, age: u64) {
+        fs::write(path, content).unwrap();
+        let modified = SystemTime::now() - Duration::from_secs(age);
+        File::options().write(true).open(path).unwrap().set_modified(modified).unwrap();
+    }
+    
+    #[test]
+    fn reloads_after_keys_are_released() {
+        let path = std::env::temp_dir().join(format!("dual-key-remap-watch-{}.txt", std::process::id()));
+        write_config(&path, CAPS_CONFIG, 60);
+        
+        let mut manager = RemapManager::new(parse_config(CAPS_CONFIG).unwrap(), RecordingSink::default());
+        let mut watcher = ConfigWatcher::new(path.clone());
+        
+        // 解析失败时保留旧配置
+        write_config(&path, "remap_key=NOT_A_KEY\n", 30);
+        watcher.poll(&mut manager, 1000);
+        assert!(manager.handle_input(vk("CAPSLOCK"), 0x3A, Direction::Down, InputSource::Physical, 1010));
+        
+        // CAPSLOCK 仍按住，新配置要等它释放后才生效
+        write_config(&path, TAB_CONFIG, 0);
+        watcher.poll(&mut manager, 2000);
+        assert!(manager.handle_input(vk("CAPSLOCK"), 0x3A, Direction::Up, InputSource::Physical, 2010));
+        assert!(!manager.handle_input(vk("TAB"), 0x0F, Direction::Down, InputSource::Physical, 2020));
+        assert!(!manager.handle_input(vk("TAB"), 0x0F, Direction::Up, InputSource::Physical, 2030));
+        
+        watcher.poll(&mut manager, 2040);
+        assert!(!manager.handle_input(vk("CAPSLOCK"), 0x3A, Direction::Down, InputSource::Physical, 2050));
+        assert!(manager.handle_input(vk("TAB"), 0x0F, Direction::Down, InputSource::Physical, 2060));
+        
+        let _ = fs::remove_file(&path);
+    }
+}